@@ -0,0 +1,98 @@
+use std::fmt::{Display, Formatter};
+
+use clap::ValueEnum;
+use png::{BitDepth, ColorType, Encoder};
+use rust_fp_common::rust_fp2_dbus::Image;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ImageFormat {
+    Pgm,
+    Png,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// Only 1 to 16 bits per pixel can be saved
+    BitsPerPixel(u8),
+    Png(png::EncodingError),
+}
+
+impl std::error::Error for Error {}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::BitsPerPixel(bits_per_pixel) => {
+                write!(
+                    f,
+                    "Can't save an image with {} bits per pixel",
+                    bits_per_pixel
+                )
+            }
+            Self::Png(e) => {
+                write!(f, "Error encoding PNG: {:#?}", e)
+            }
+        }
+    }
+}
+
+/// The grayscale value of every pixel. Pixels with more than 8 bits are 2 little endian bytes.
+fn pixels(image: &Image) -> Result<Vec<u16>, Error> {
+    match image.bits_per_pixel {
+        1..=8 => Ok(image.pixels.iter().map(|&pixel| pixel.into()).collect()),
+        9..=16 => Ok(image
+            .pixels
+            .chunks_exact(2)
+            .map(|pixel| u16::from_le_bytes([pixel[0], pixel[1]]))
+            .collect()),
+        bits_per_pixel => Err(Error::BitsPerPixel(bits_per_pixel)),
+    }
+}
+
+/// Encodes a grayscale image from the sensor
+pub fn encode_image(image: &Image, format: ImageFormat) -> Result<Vec<u8>, Error> {
+    let pixels = pixels(image)?;
+    let wide = image.bits_per_pixel > 8;
+    match format {
+        ImageFormat::Pgm => {
+            // PGM can have any max value, so the pixels are saved as they are
+            let max_value = (1u32 << image.bits_per_pixel) - 1;
+            let mut file =
+                format!("P5\n{} {}\n{max_value}\n", image.width, image.height).into_bytes();
+            for pixel in pixels {
+                match wide {
+                    true => file.extend(pixel.to_be_bytes()),
+                    false => file.push(pixel as u8),
+                }
+            }
+            Ok(file)
+        }
+        ImageFormat::Png => {
+            // PNG only has 8 and 16 bit grayscale, so pixels are scaled up to fill the range
+            let (bit_depth, bits) = match wide {
+                true => (BitDepth::Sixteen, 16),
+                false => (BitDepth::Eight, 8),
+            };
+            let shift = bits - image.bits_per_pixel;
+            let data = pixels
+                .into_iter()
+                .flat_map(|pixel| {
+                    let pixel = pixel << shift;
+                    match wide {
+                        true => pixel.to_be_bytes().to_vec(),
+                        false => vec![pixel as u8],
+                    }
+                })
+                .collect::<Vec<_>>();
+            let mut file = Vec::new();
+            let mut encoder = Encoder::new(&mut file, image.width, image.height);
+            encoder.set_color(ColorType::Grayscale);
+            encoder.set_depth(bit_depth);
+            encoder
+                .write_header()
+                .and_then(|mut writer| writer.write_image_data(&data))
+                .map_err(Error::Png)?;
+            Ok(file)
+        }
+    }
+}
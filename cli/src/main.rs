@@ -1,154 +1,246 @@
-use async_std::{io::stdout, main};
+use async_std::fs::{remove_file, write};
+use async_std::main;
 use std::error::Error;
+use std::io::ErrorKind;
+use std::path::PathBuf;
 
-use clap::{Parser, Subcommand};
-use postcard::from_bytes;
-use rust_fp_common::{enroll_step_dbus_result::EnrollStepDbusOutput, rust_fp_dbus::RustFpProxy};
-use zbus::export::futures_util::AsyncWriteExt;
+use clap::{Parser, Subcommand, ValueEnum};
+use rust_fp_common::fp_file::{get_current_username, get_fp_file};
+use rust_fp_common::get_templates::get_templates;
+use rust_fp_common::manager_dbus::ManagerProxy;
+use rust_fp_common::rust_fp2_dbus::{CaptureType, EnrollStepStatus, MatchStatus, RustFp2Proxy};
+use zbus::zvariant::OwnedObjectPath;
 use zbus::Connection;
 
-use rust_fp::fingerprint_driver::{EnrollStepOutput, MatchOutput, MatchedOutput};
-use rust_fp_common::get_templates::get_templates;
-use rust_fp_common::set_templates::set_templates;
+use crate::image_file::{encode_image, ImageFormat};
+
+mod image_file;
 
 #[derive(Parser)]
 #[command(version, about)]
 struct Cli {
+    /// The name or object path of the fingerprint sensor to use, if there are multiple. The default device is used if it's not set.
+    #[arg(long, global = true)]
+    device: Option<String>,
     #[command(subcommand)]
     command: Commands,
 }
 
+/// Connects to the device that the user picked
+async fn get_proxy(device: Option<&str>) -> Result<RustFp2Proxy<'static>, Box<dyn Error>> {
+    let connection = Connection::system().await?;
+    let path = match device {
+        Some(path) if path.starts_with('/') => OwnedObjectPath::try_from(path)?,
+        Some(name) => {
+            ManagerProxy::new(&connection)
+                .await?
+                .get_device(name)
+                .await?
+        }
+        None => return Ok(RustFp2Proxy::new(&connection).await?),
+    };
+    Ok(RustFp2Proxy::builder(&connection)
+        .path(path)?
+        .build()
+        .await?)
+}
+
+/// What to capture with `rust-fp capture`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum CaptureKind {
+    /// A finger
+    Simple,
+    /// A checkerboard test pattern, to find dead pixels. Doesn't need a finger.
+    Pattern,
+    /// A finger, captured the way the sensor's image quality test does
+    QualityTest,
+    /// The sensor right after resetting it. Doesn't need a finger.
+    ResetTest,
+}
+
+impl From<CaptureKind> for CaptureType {
+    fn from(kind: CaptureKind) -> Self {
+        match kind {
+            CaptureKind::Simple => Self::Simple,
+            CaptureKind::Pattern => Self::Pattern,
+            CaptureKind::QualityTest => Self::QualityTest,
+            CaptureKind::ResetTest => Self::ResetTest,
+        }
+    }
+}
+
 #[derive(Subcommand)]
 enum Commands {
+    /// List the fingerprint sensors. The first one is the default device.
+    Devices,
     /// Get the maximum number of templates that the fingerprint sensor can have stored
     GetMaxTemplates,
+    /// Show what the fingerprint sensor can do
+    Capabilities,
     Add {
         label: String,
+        /// Enroll a finger for another user instead of yourself
+        #[arg(long)]
+        user: Option<String>,
     },
     List,
     /// Remove a fingerprint template
     Remove {
-        label: String,
+        #[arg(required_unless_present = "id")]
+        label: Option<String>,
+        /// Remove the template with this id, from `rust-fp list`, instead of by label
+        #[arg(long, conflicts_with = "label")]
+        id: Option<String>,
     },
     /// Remove all stored fingerprints for a user
     Clear,
-    /// Test out the fingerprint sensor by matching a finger, and save the updated template if it was updated
+    /// Test out the fingerprint sensor by matching a finger. The daemon saves the template if it was updated.
     Match,
-    /// Prints a template in binary to stdout
-    DownloadTemplate {
-        label: String,
+    /// Give templates saved by older versions of rust-fp in your home dir to the daemon, and delete the old file
+    Migrate,
+    /// Encrypt every user's stored templates with a new key. Needs admin authentication.
+    Rekey,
+    /// Stop using the seed that older versions of rust-fp used, by making the sensor encrypt templates with a new random seed.
+    /// Fingerprints that need the old seed are deleted and need to be enrolled again. Needs admin authentication.
+    NewSeed,
+    /// Show low level information about the fingerprint sensor
+    Info,
+    /// Test the fingerprint sensor's hardware and firmware
+    Doctor,
+    /// Save a raw image from the fingerprint sensor, to check if it's dirty or broken. Needs admin authentication.
+    Capture {
+        /// The file to save the image to
+        output: PathBuf,
+        #[arg(long, value_enum, default_value_t = ImageFormat::Png)]
+        format: ImageFormat,
+        #[arg(long = "type", value_enum, default_value_t = CaptureKind::Simple)]
+        capture_type: CaptureKind,
     },
+    /// Stop an enroll or match that is waiting for a finger
+    Cancel,
 }
 
 #[main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    match Cli::parse().command {
-        Commands::GetMaxTemplates => {
+    let cli = Cli::parse();
+    let device = cli.device.as_deref();
+    match cli.command {
+        Commands::Devices => {
             let connection = Connection::system().await?;
-            let proxy = RustFpProxy::new(&connection).await?;
+            for device in ManagerProxy::new(&connection).await?.get_devices().await? {
+                println!(
+                    "{} {}{}",
+                    device.name,
+                    device.path.as_str(),
+                    match device.available {
+                        true => "",
+                        false => " (unavailable)",
+                    }
+                );
+            }
+        }
+        Commands::GetMaxTemplates => {
+            let proxy = get_proxy(device).await?;
             let max_templates = proxy.get_max_templates().await?;
             println!("Max templates: {max_templates}");
         }
-        Commands::Add { label } => {
-            let mut templates = get_templates().await?;
-            match templates.contains_key(&label) {
-                false => {
-                    let connection = Connection::system().await?;
-                    let proxy = RustFpProxy::new(&connection).await?;
-                    let mut id = None;
-                    let template = loop {
-                        println!("Touch the FP sensor");
-                        // FIXME: Don't exit if there is a LowQuality error. Just do the enroll step again.
-                        let output = loop {
-                            let output: EnrollStepDbusOutput =
-                                from_bytes(&proxy.enroll_step(id.unwrap_or_default()).await?)?;
-                            id = Some(output.id);
-                            match output.result {
-                                Ok(output) => {
-                                    break output;
-                                }
-                                Err(error) => {
-                                    println!("{error:?}. Try again.");
-                                }
-                            }
-                        };
-                        match output {
-                            EnrollStepOutput::InProgress(percentage) => {
-                                println!("Enroll progress: {percentage}%");
-                            }
-                            EnrollStepOutput::Complete(template) => {
-                                break template;
-                            }
-                        }
-                    };
-                    println!("Enroll complete");
-                    templates.insert(label, template);
-                    set_templates(&templates).await?;
-                    println!("Saved template to file");
-                    Ok(())
+        Commands::Capabilities => {
+            let proxy = get_proxy(device).await?;
+            println!("{:#?}", proxy.get_capabilities().await?);
+        }
+        Commands::Add { label, user } => {
+            let proxy = get_proxy(device).await?;
+            let finger_detect = proxy.get_capabilities().await?.finger_detect;
+            let mut id = None;
+            loop {
+                println!("Touch the FP sensor");
+                let step = proxy
+                    .enroll_finger(
+                        user.as_deref().unwrap_or_default(),
+                        id.unwrap_or_default(),
+                        &label,
+                    )
+                    .await?;
+                id = Some(step.id);
+                match step.status {
+                    EnrollStepStatus::InProgress => {
+                        println!("Enroll progress: {}%", step.percentage);
+                    }
+                    EnrollStepStatus::Complete => {
+                        break Ok(());
+                    }
+                    EnrollStepStatus::LowQuality => {
+                        println!("Low quality. Try again.");
+                    }
+                    EnrollStepStatus::Immobile => {
+                        println!("Move your finger a bit between touches. Try again.");
+                    }
+                    EnrollStepStatus::Cancelled => {
+                        break Err("Enrolling was cancelled".to_owned());
+                    }
+                    EnrollStepStatus::Failed => {
+                        break Err(format!("Error enrolling: {}", step.error));
+                    }
+                }
+                // So that the next step gets a new touch instead of the finger that is still on the sensor
+                if finger_detect {
+                    println!("Lift your finger");
+                    proxy.wait_finger_up().await?;
                 }
-                true => Err("A fingerprint with that label already exists"),
             }?;
+            println!("Enroll complete. Saved template.");
         }
         Commands::List => {
-            let templates = get_templates().await?;
-            println!("Fingerprints saved for this user:  {:#?}", templates.keys());
-        }
-        Commands::Remove { label } => {
-            let mut templates = get_templates().await?;
-            match templates.remove(&label) {
-                Some(_removed_template) => {
-                    set_templates(&templates).await?;
-                    println!("Removed template {:#?}", label);
-                    Ok(())
+            let proxy = get_proxy(device).await?;
+            let fingers = proxy.list_fingers_info().await?;
+            println!(
+                "Fingerprints saved for this user:  {:#?}",
+                fingers
+                    .iter()
+                    .map(|finger| &finger.label)
+                    .collect::<Vec<_>>()
+            );
+            for finger in fingers {
+                println!("{:#?}: template id {}", finger.label, finger.id);
+                if finger.other_sensor {
+                    println!(
+                        "Warning: {:#?} was enrolled with a different sensor ({} {} {}), so it won't match on this sensor. If that sensor isn't used anymore, remove it and enroll it again.",
+                        finger.label, finger.driver, finger.sensor_id, finger.hardware_version
+                    );
                 }
-                None => Err(format!(
-                    "Template {:#?} doesn't exist. Existing templates: {:#?}",
-                    label,
-                    templates.keys().collect::<Vec<_>>()
-                )),
-            }?;
+            }
+        }
+        Commands::Remove { label, id } => {
+            let proxy = get_proxy(device).await?;
+            let label = match (label, id) {
+                (Some(label), _) => {
+                    proxy.delete_finger(&label).await?;
+                    label
+                }
+                (None, Some(id)) => proxy.delete_finger_by_id(&id).await?,
+                (None, None) => unreachable!("clap requires a label or an id"),
+            };
+            println!("Removed template {:#?}", label);
         }
         Commands::Clear => {
-            set_templates(&Default::default()).await?;
+            let proxy = get_proxy(device).await?;
+            proxy.delete_all_fingers().await?;
             println!("Cleared templates");
         }
         Commands::Match => {
-            let mut templates = get_templates().await?;
-            if !templates.is_empty() {
-                let connection = Connection::system().await?;
-                let proxy = RustFpProxy::new(&connection).await?;
-                let templates_vec = templates.iter().collect::<Vec<_>>();
+            let proxy = get_proxy(device).await?;
+            if !proxy.list_fingers().await?.is_empty() {
                 println!("Ready to match...");
-                let output: MatchOutput = from_bytes(
-                    &proxy
-                        .match_templates(
-                            templates_vec
-                                .iter()
-                                .map::<Vec<u8>, _>(|(_k, v)| v.to_vec())
-                                .collect(),
-                        )
-                        .await?,
-                )?;
-                println!("Output: {output:?}");
-                match output {
-                    MatchOutput::Match(MatchedOutput {
-                        index,
-                        updated_template,
-                    }) => {
-                        let matched_label = templates_vec[index].0;
-                        println!("Matched: {matched_label}.");
-                        if let Some(updated_template) = updated_template {
-                            println!("Template was updated. Saving updated template...");
-                            templates.insert(matched_label.to_owned(), updated_template);
-                            set_templates(&templates).await?;
-                            println!("Saved updated template");
-                        }
+                let output = proxy.verify("").await?;
+                println!("Output: {:?}", output.status);
+                match output.status {
+                    MatchStatus::Match => {
+                        println!("Matched: {}.", output.label);
                     }
-                    MatchOutput::NoMatch(error) => {
+                    status => {
                         println!("No match");
-                        if let Some(error) = error {
-                            println!("Error matching: {error:?}");
+                        if status != MatchStatus::NoMatch {
+                            println!("Error matching: {status:?}");
                         }
                     }
                 }
@@ -156,16 +248,129 @@ async fn main() -> Result<(), Box<dyn Error>> {
                 println!("No templates saved. Not matching.");
             }
         }
-        Commands::DownloadTemplate { label } => {
-            let templates = get_templates().await?;
-            match templates.get(&label) {
-                Some(template) => {
-                    stdout().write_all(template).await?;
+        Commands::Migrate => {
+            let username = get_current_username()?;
+            let templates = get_templates(&username).await?;
+            if !templates.is_empty() {
+                let labels = templates.keys().cloned().collect::<Vec<_>>();
+                let proxy = get_proxy(device).await?;
+                let imported = proxy
+                    .import_fingers(
+                        templates
+                            .into_iter()
+                            .map(|(label, entry)| (label, entry.template))
+                            .collect(),
+                    )
+                    .await?;
+                println!("Imported templates: {:#?}", imported);
+                let skipped = labels
+                    .into_iter()
+                    .filter(|label| !imported.contains(label))
+                    .collect::<Vec<_>>();
+                if !skipped.is_empty() {
+                    return Err(format!(
+                        "Not deleting the old templates file because these labels already exist: {:#?}. Remove them with `rust-fp remove` and migrate again.",
+                        skipped
+                    )
+                    .into());
                 }
-                None => {
-                    println!("Template does not exist");
+            } else {
+                println!("No templates to migrate");
+            }
+            remove_file(get_fp_file(&username)?)
+                .await
+                .or_else(|e| match e.kind() {
+                    ErrorKind::NotFound => Ok(()),
+                    _ => Err(e),
+                })?;
+            println!("Deleted old templates file");
+        }
+        Commands::Rekey => {
+            let proxy = get_proxy(device).await?;
+            proxy.rekey().await?;
+            println!("Rekeyed templates");
+        }
+        Commands::NewSeed => {
+            let proxy = get_proxy(device).await?;
+            proxy.new_seed().await?;
+            println!("Replaced the legacy seed and deleted the fingerprints that need it. Reboot, so that the sensor uses the new seed, and then enroll those fingers again.");
+        }
+        Commands::Info => {
+            let proxy = get_proxy(device).await?;
+            let info = proxy.device_info().await?;
+            println!(
+                "Vendor: {:#010x}, product: {:#010x}, model: {:#010x}, version: {}",
+                info.vendor_id, info.product_id, info.model_id, info.version
+            );
+            println!(
+                "Sensor: {}x{} pixels, {} bits per pixel, {} byte frames",
+                info.width, info.height, info.bits_per_pixel, info.frame_size
+            );
+            println!(
+                "Templates: {} of {} loaded, {} bytes each, version {}, dirty mask {:#x}",
+                info.loaded_templates,
+                info.max_templates,
+                info.template_size,
+                info.template_version,
+                info.dirty_templates
+            );
+            println!(
+                "Protocol: versions {:#x}, max request {} bytes, max response {} bytes",
+                info.protocol_versions, info.max_request_size, info.max_response_size
+            );
+            println!(
+                "Seed: {}",
+                match info.seed_set {
+                    true => "set",
+                    false => "not set",
                 }
+            );
+        }
+        Commands::Doctor => {
+            let proxy = get_proxy(device).await?;
+            let report = proxy.self_test().await?;
+            println!(
+                "{} sensor {} hardware version {}",
+                report.driver, report.sensor_id, report.hardware_version
+            );
+            if !report.firmware_version.is_empty() {
+                println!("Firmware: {}", report.firmware_version);
             }
+            for check in report.checks {
+                println!(
+                    "[{}] {}: {}",
+                    match check.passed {
+                        true => " OK ",
+                        false => "FAIL",
+                    },
+                    check.name,
+                    check.details
+                );
+            }
+            if !report.passed {
+                Err("Some checks failed")?;
+            }
+        }
+        Commands::Capture {
+            output,
+            format,
+            capture_type,
+        } => {
+            let proxy = get_proxy(device).await?;
+            if matches!(capture_type, CaptureKind::Simple | CaptureKind::QualityTest) {
+                println!("Touch the FP sensor");
+            }
+            let image = proxy.capture_image(capture_type.into()).await?;
+            write(&output, encode_image(&image, format)?).await?;
+            println!(
+                "Saved {}x{} image with {} bits per pixel to {:?}",
+                image.width, image.height, image.bits_per_pixel, output
+            );
+        }
+        Commands::Cancel => {
+            let proxy = get_proxy(device).await?;
+            proxy.cancel().await?;
+            println!("Cancelled");
         }
     }
     Ok(())
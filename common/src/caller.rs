@@ -0,0 +1,86 @@
+use nix::unistd::{Uid, User};
+use zbus::export::futures_util::StreamExt;
+use zbus::fdo::{self, DBusProxy};
+use zbus::message::Header;
+use zbus::names::OwnedUniqueName;
+use zbus::Connection;
+
+/// A client on the bus. Its unique name is never used by another connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Caller {
+    pub name: OwnedUniqueName,
+    pub uid: Uid,
+}
+
+/// Gets the uid of the process that sent a D-Bus message
+pub async fn get_caller_uid(connection: &Connection, header: &Header<'_>) -> fdo::Result<Uid> {
+    let sender = header
+        .sender()
+        .ok_or_else(|| fdo::Error::Failed("Unknown sender".into()))?;
+    Ok(Uid::from_raw(
+        DBusProxy::new(connection)
+            .await?
+            .get_connection_unix_user(sender.to_owned().into())
+            .await?,
+    ))
+}
+
+/// Gets the client that sent a D-Bus message
+pub async fn get_caller(connection: &Connection, header: &Header<'_>) -> fdo::Result<Caller> {
+    let name = header
+        .sender()
+        .ok_or_else(|| fdo::Error::Failed("Unknown sender".into()))?
+        .to_owned()
+        .into();
+    Ok(Caller {
+        name,
+        uid: get_caller_uid(connection, header).await?,
+    })
+}
+
+/// Waits until a client disconnects from the bus
+pub async fn wait_until_disconnected(
+    connection: &Connection,
+    name: &OwnedUniqueName,
+) -> zbus::Result<()> {
+    let dbus_proxy = DBusProxy::new(connection).await?;
+    let mut name_owner_changed = dbus_proxy
+        .receive_name_owner_changed_with_args(&[(0, name.as_str())])
+        .await?;
+    // The client could have disconnected before we started watching
+    if dbus_proxy.name_has_owner(name.as_ref().into()).await? {
+        while let Some(signal) = name_owner_changed.next().await {
+            if signal.args()?.new_owner().is_none() {
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Looks up a user by name, or the caller if `username` is empty. Doesn't check if the caller can access the user.
+pub fn get_user(caller_uid: Uid, username: &str) -> fdo::Result<User> {
+    match username {
+        "" => User::from_uid(caller_uid),
+        username => User::from_name(username),
+    }
+    .map_err(|e| fdo::Error::Failed(format!("Error getting user: {e:?}")))?
+    .ok_or_else(|| fdo::Error::Failed(format!("User {username:?} doesn't exist")))
+}
+
+/// Gets the user that the caller is asking for, which is the caller if `username` is empty.
+/// Only root can ask for other users.
+pub async fn get_target_user(
+    connection: &Connection,
+    header: &Header<'_>,
+    username: &str,
+) -> fdo::Result<User> {
+    let caller_uid = get_caller_uid(connection, header).await?;
+    let user = get_user(caller_uid, username)?;
+    match user.uid == caller_uid || caller_uid.is_root() {
+        true => Ok(user),
+        false => Err(fdo::Error::AccessDenied(
+            "Only root can access other users' fingerprints".into(),
+        )),
+    }
+}
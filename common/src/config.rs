@@ -0,0 +1,84 @@
+use std::fmt::{Display, Formatter};
+use std::fs::read_to_string;
+use std::io::{self, ErrorKind};
+
+use toml_edit::{DocumentMut, TomlError};
+
+/// System wide settings. Every setting is optional.
+/// ```toml
+/// # Where the D-Bus interface stores templates and the seed
+/// templates_dir = "/var/lib/rust-fp"
+/// # Encrypt templates files with a key that only root can read
+/// encrypt_templates = true
+/// # Where the key is stored. Keep it out of backups of the templates.
+/// storage_key_file = "/etc/rust-fp/storage-key"
+/// ```
+pub const CONFIG_FILE: &str = "/etc/rust-fp/config.toml";
+
+#[derive(Debug)]
+pub enum Error {
+    Read(io::Error),
+    Parse(TomlError),
+    /// A setting has the wrong type
+    Invalid(&'static str),
+}
+
+impl std::error::Error for Error {}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Read(e) => {
+                write!(f, "Error reading {}: {:#?}", CONFIG_FILE, e)
+            }
+            Self::Parse(e) => {
+                write!(f, "Error parsing {}: {:#?}", CONFIG_FILE, e)
+            }
+            Self::Invalid(key) => {
+                write!(f, "Invalid value for {} in {}", key, CONFIG_FILE)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    pub templates_dir: Option<String>,
+    pub encrypt_templates: bool,
+    pub storage_key_file: Option<String>,
+}
+
+/// Reads [`CONFIG_FILE`]. If it doesn't exist, the default config is used.
+pub fn get_config() -> Result<Config, Error> {
+    let config = match read_to_string(CONFIG_FILE) {
+        Ok(config) => config,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Default::default()),
+        Err(e) => return Err(Error::Read(e)),
+    };
+    let document = config.parse::<DocumentMut>().map_err(Error::Parse)?;
+    let templates_dir = match document.get("templates_dir") {
+        Some(item) => Some(
+            item.as_str()
+                .ok_or(Error::Invalid("templates_dir"))?
+                .to_owned(),
+        ),
+        None => None,
+    };
+    let encrypt_templates = match document.get("encrypt_templates") {
+        Some(item) => item.as_bool().ok_or(Error::Invalid("encrypt_templates"))?,
+        None => false,
+    };
+    let storage_key_file = match document.get("storage_key_file") {
+        Some(item) => Some(
+            item.as_str()
+                .ok_or(Error::Invalid("storage_key_file"))?
+                .to_owned(),
+        ),
+        None => None,
+    };
+    Ok(Config {
+        templates_dir,
+        encrypt_templates,
+        storage_key_file,
+    })
+}
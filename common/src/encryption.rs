@@ -0,0 +1,164 @@
+use std::borrow::Cow;
+use std::fmt::{Debug, Display, Formatter};
+use std::io::{self, ErrorKind};
+use std::os::unix::fs::OpenOptionsExt;
+
+use async_std::fs::{read, OpenOptions};
+use async_std::io::WriteExt;
+use chacha20poly1305::aead::{self, Aead, KeyInit, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use rand::random;
+
+/// The start of encrypted templates files. It is followed by a random nonce and the encrypted contents of an unencrypted templates file.
+pub const ENCRYPTED_MAGIC: [u8; 8] = *b"RUST-FPE";
+const NONCE_LEN: usize = 24;
+
+#[derive(Debug)]
+pub enum Error {
+    ReadKey(io::Error),
+    WriteKey(io::Error),
+    /// The key file doesn't have 32 bytes
+    KeyLength(usize),
+    Encrypt(aead::Error),
+    /// The file is encrypted, but there is no key
+    MissingKey,
+    /// None of the keys could decrypt the file, or it was changed after it was encrypted
+    Decrypt,
+}
+
+impl std::error::Error for Error {}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ReadKey(e) => {
+                write!(f, "Error reading key: {:#?}", e)
+            }
+            Self::WriteKey(e) => {
+                write!(f, "Error writing key: {:#?}", e)
+            }
+            Self::KeyLength(length) => {
+                write!(f, "Key file has {} bytes instead of 32", length)
+            }
+            Self::Encrypt(e) => {
+                write!(f, "Error encrypting: {:#?}", e)
+            }
+            Self::MissingKey => {
+                write!(f, "Templates file is encrypted, but there is no key")
+            }
+            Self::Decrypt => {
+                write!(
+                    f,
+                    "Templates file couldn't be decrypted. It was encrypted with a different key, or it was changed."
+                )
+            }
+        }
+    }
+}
+
+/// A key that templates files are encrypted with, so that copies of the files are useless without the key
+#[derive(Clone)]
+pub struct StorageKey([u8; 32]);
+
+impl Debug for StorageKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        // Don't put the key in logs
+        f.write_str("StorageKey(..)")
+    }
+}
+
+impl StorageKey {
+    pub fn random() -> Self {
+        Self(random())
+    }
+
+    /// Returns `None` if the key file doesn't exist
+    pub async fn read(path: &str) -> Result<Option<Self>, Error> {
+        match read(path).await {
+            Ok(key) => Ok(Some(Self(
+                key.try_into()
+                    .map_err(|key: Vec<u8>| Error::KeyLength(key.len()))?,
+            ))),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(Error::ReadKey(e)),
+        }
+    }
+
+    /// Saves the key in a file that only its owner can read
+    pub async fn write(&self, path: &str) -> Result<(), Error> {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(path)
+            .await
+            .map_err(Error::WriteKey)?;
+        file.write_all(&self.0).await.map_err(Error::WriteKey)?;
+        file.sync_all().await.map_err(Error::WriteKey)?;
+        Ok(())
+    }
+
+    fn cipher(&self) -> XChaCha20Poly1305 {
+        XChaCha20Poly1305::new(&self.0.into())
+    }
+}
+
+/// The keys used to save and read templates files
+#[derive(Debug, Clone, Default)]
+pub struct StorageKeys {
+    /// Templates are saved encrypted with this key, or unencrypted if it's `None`
+    pub current: Option<StorageKey>,
+    /// Also tried when reading, for files that haven't been saved again since the key was changed
+    pub old: Vec<StorageKey>,
+}
+
+impl StorageKeys {
+    /// Encrypts the contents of a templates file with the current key, if there is one
+    pub fn encrypt(&self, contents: Vec<u8>) -> Result<Vec<u8>, Error> {
+        match &self.current {
+            Some(key) => {
+                let nonce: [u8; NONCE_LEN] = random();
+                let encrypted = key
+                    .cipher()
+                    .encrypt(
+                        &XNonce::from(nonce),
+                        Payload {
+                            msg: &contents,
+                            aad: &ENCRYPTED_MAGIC,
+                        },
+                    )
+                    .map_err(Error::Encrypt)?;
+                Ok([&ENCRYPTED_MAGIC[..], &nonce, &encrypted].concat())
+            }
+            None => Ok(contents),
+        }
+    }
+
+    /// Decrypts the contents of a templates file. Unencrypted files are returned as they are.
+    pub fn decrypt<'a>(&self, contents: &'a [u8]) -> Result<Cow<'a, [u8]>, Error> {
+        let Some(contents) = contents.strip_prefix(&ENCRYPTED_MAGIC) else {
+            return Ok(Cow::Borrowed(contents));
+        };
+        let (nonce, encrypted) = contents
+            .split_first_chunk::<NONCE_LEN>()
+            .ok_or(Error::Decrypt)?;
+        let mut keys = self.current.iter().chain(&self.old).peekable();
+        if keys.peek().is_none() {
+            return Err(Error::MissingKey);
+        }
+        keys.find_map(|key| {
+            key.cipher()
+                .decrypt(
+                    &XNonce::from(*nonce),
+                    Payload {
+                        msg: encrypted,
+                        aad: &ENCRYPTED_MAGIC,
+                    },
+                )
+                .ok()
+        })
+        .map(Cow::Owned)
+        .ok_or(Error::Decrypt)
+    }
+}
@@ -1,10 +1,16 @@
-use home::home_dir;
+use std::env;
 use std::fmt::{Display, Formatter};
 
+use nix::unistd::{getuid, User};
+
+use crate::config::{self, get_config};
+
 #[derive(Debug)]
 pub enum Error {
+    UnknownUser,
     HomeDir,
     PathBufToStr,
+    Config(config::Error),
 }
 
 impl std::error::Error for Error {}
@@ -12,26 +18,145 @@ impl std::error::Error for Error {}
 impl Display for Error {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
+            Self::UnknownUser => {
+                write!(f, "User isn't in the user database")
+            }
             Self::HomeDir => {
                 write!(f, "Error getting home dir")
             }
             Self::PathBufToStr => {
                 write!(f, "Couldn't convert path buf to str")
             }
+            Self::Config(e) => {
+                write!(f, "Error getting config: {}", e)
+            }
+        }
+    }
+}
+
+/// Where the daemon stores every user's templates by default. Only root can access it.
+pub const DAEMON_FP_DIR: &str = "/var/lib/rust-fp";
+/// Overrides where the daemon stores templates, for example to run it without root
+pub const TEMPLATES_DIR_ENV_VAR: &str = "RUST_FP_TEMPLATES_DIR";
+
+/// Where the daemon stores every user's templates and the seed. The first of these that is set is used:
+/// 1. [`TEMPLATES_DIR_ENV_VAR`]
+/// 2. `templates_dir` in [`config::CONFIG_FILE`]
+/// 3. `$XDG_DATA_HOME/rust-fp`, if the daemon isn't running as root
+/// 4. [`DAEMON_FP_DIR`]
+///
+/// None of these need a home dir.
+pub fn get_templates_dir() -> Result<String, Error> {
+    let from_env = |var| {
+        env::var_os(var)
+            .filter(|value| !value.is_empty())
+            .map(|value| value.into_string().map_err(|_value| Error::PathBufToStr))
+            .transpose()
+    };
+    if let Some(dir) = from_env(TEMPLATES_DIR_ENV_VAR)? {
+        return Ok(dir);
+    }
+    if let Some(dir) = get_config().map_err(Error::Config)?.templates_dir {
+        return Ok(dir);
+    }
+    if !getuid().is_root() {
+        if let Some(data_home) = from_env("XDG_DATA_HOME")? {
+            return Ok(format!("{data_home}/rust-fp"));
+        }
+    }
+    Ok(DAEMON_FP_DIR.into())
+}
+
+/// The seed that the fingerprint sensor uses to encrypt templates
+pub fn get_seed_file(templates_dir: &str) -> String {
+    format!("{templates_dir}/seed")
+}
+
+/// The name of the user running this process, from the user database
+pub fn get_current_username() -> Result<String, Error> {
+    Ok(User::from_uid(getuid())
+        .ok()
+        .flatten()
+        .ok_or(Error::UnknownUser)?
+        .name)
+}
+
+/// The home dir of a user, looked up by name in the user database (like `getpwnam`),
+/// so that it's the user's home dir even if `$HOME` isn't set or is another user's
+pub fn get_home_dir(username: &str) -> Result<String, Error> {
+    User::from_name(username)
+        .ok()
+        .flatten()
+        .ok_or(Error::UnknownUser)?
+        .dir
+        .into_os_string()
+        .into_string()
+        .map_err(|_dir| Error::PathBufToStr)
+}
+
+/// The legacy location, where the CLI and PAM module used to store a user's templates themselves.
+/// Templates are stored by the D-Bus interface in [`get_templates_dir`] now, so this is only used to migrate old templates.
+pub fn get_fp_dir(username: &str) -> Result<String, Error> {
+    Ok(format!("{}/.var", get_home_dir(username)?))
+}
+
+pub fn get_fp_file(username: &str) -> Result<String, Error> {
+    Ok(format!("{}/cros-fp-templates", get_fp_dir(username)?))
+}
+
+pub fn get_daemon_fp_dir(templates_dir: &str, uid: u32) -> String {
+    format!("{templates_dir}/{uid}")
+}
+
+pub fn get_daemon_fp_file(templates_dir: &str, uid: u32) -> String {
+    format!("{}/templates", get_daemon_fp_dir(templates_dir, uid))
+}
+
+/// The previous version of a templates file, in case the file gets corrupted
+pub fn get_backup_fp_file(fp_file: &str) -> String {
+    format!("{fp_file}.bak")
+}
+
+/// Where templates get written before replacing the templates file
+pub fn get_temp_fp_file(fp_file: &str) -> String {
+    format!("{fp_file}.tmp")
+}
+
+/// Locked while a templates file is being used. It's separate from the templates file because that file gets replaced.
+pub fn get_lock_fp_file(fp_file: &str) -> String {
+    format!("{fp_file}.lock")
+}
+
+/// Where the daemon stores the key that it encrypts templates files with by default.
+/// It's outside of the templates dir, so that backups of the templates dir don't have the key.
+pub const STORAGE_KEY_FILE: &str = "/etc/rust-fp/storage-key";
+
+/// Where the daemon stores the key that it encrypts templates files with. The first of these that is set is used:
+/// 1. `storage_key_file` in [`config::CONFIG_FILE`]
+/// 2. `$XDG_CONFIG_HOME/rust-fp/storage-key`, if the daemon isn't running as root
+/// 3. [`STORAGE_KEY_FILE`]
+pub fn get_storage_key_file() -> Result<String, Error> {
+    if let Some(file) = get_config().map_err(Error::Config)?.storage_key_file {
+        return Ok(file);
+    }
+    if !getuid().is_root() {
+        if let Some(config_home) = env::var_os("XDG_CONFIG_HOME").filter(|value| !value.is_empty())
+        {
+            let config_home = config_home
+                .into_string()
+                .map_err(|_value| Error::PathBufToStr)?;
+            return Ok(format!("{config_home}/rust-fp/storage-key"));
         }
     }
+    Ok(STORAGE_KEY_FILE.into())
 }
 
-pub fn get_fp_dir() -> Result<String, Error> {
-    Ok(format!(
-        "{}/.var",
-        home_dir()
-            .ok_or(Error::HomeDir)?
-            .to_str()
-            .ok_or(Error::PathBufToStr)?
-    ))
+/// Where older versions of rust-fp stored the key, next to the templates
+pub fn get_legacy_storage_key_file(templates_dir: &str) -> String {
+    format!("{templates_dir}/storage-key")
 }
 
-pub fn get_fp_file() -> Result<String, Error> {
-    Ok(format!("{}/cros-fp-templates", get_fp_dir()?))
+/// A key that templates are being re-encrypted with. It replaces the current key once every file is re-encrypted.
+pub fn get_new_storage_key_file(storage_key_file: &str) -> String {
+    format!("{storage_key_file}.new")
 }
@@ -5,17 +5,29 @@ use std::io::ErrorKind;
 use crate::fp_file;
 use async_std::fs::OpenOptions;
 use async_std::io::ReadExt;
+use log::warn;
 use rmp_serde::decode;
 
-use crate::fp_file::get_fp_file;
-use crate::template::Templates;
+use crate::encryption::{self, StorageKeys};
+use crate::fp_file::{get_backup_fp_file, get_fp_file};
+use crate::lock_templates::{self, lock_templates, LockKind};
+use crate::template::{from_legacy, LegacyTemplates, Templates, FORMAT_VERSION, MAGIC};
 
 #[derive(Debug)]
 pub enum Error {
     Open(io::Error),
     FpFile(fp_file::Error),
+    Lock(lock_templates::Error),
     Read(io::Error),
+    Decrypt(encryption::Error),
     Decode(decode::Error),
+    /// The file was saved by a newer version of rust-fp
+    UnsupportedVersion(u32),
+    /// The file couldn't be decrypted or decoded, and neither could the backup
+    Corrupted {
+        error: Box<Error>,
+        backup_error: Box<Error>,
+    },
 }
 
 impl std::error::Error for Error {}
@@ -29,34 +41,212 @@ impl Display for Error {
             Self::FpFile(e) => {
                 write!(f, "Error getting fp file: {:#?}", e)
             }
+            Self::Lock(e) => {
+                write!(f, "Error locking file: {}", e)
+            }
             Self::Read(e) => {
                 write!(f, "Error reading file: {:#?}", e)
             }
+            Self::Decrypt(e) => {
+                write!(f, "Error decrypting file: {}", e)
+            }
             Self::Decode(e) => {
                 write!(f, "Error decoding file: {:#?}", e)
             }
+            Self::UnsupportedVersion(version) => {
+                write!(
+                    f,
+                    "Templates file has format version {}, but only version {} is supported",
+                    version, FORMAT_VERSION
+                )
+            }
+            Self::Corrupted {
+                error,
+                backup_error,
+            } => {
+                write!(
+                    f,
+                    "Templates file is corrupted: {}. The backup couldn't be used either: {}",
+                    error, backup_error
+                )
+            }
         }
     }
 }
 
-pub async fn get_templates() -> Result<Templates, Error> {
-    match OpenOptions::new()
-        .read(true)
-        .open(get_fp_file().map_err( Error::FpFile)?)
-        .await
-    {
+/// Templates in a user's own file are never encrypted
+pub async fn get_templates(username: &str) -> Result<Templates, Error> {
+    get_templates_from(
+        &get_fp_file(username).map_err(Error::FpFile)?,
+        &StorageKeys::default(),
+    )
+    .await
+}
+
+/// Reads templates from a specific file instead of a user's own file, decrypting it if it's encrypted.
+/// If the file is corrupted or can't be decrypted, the backup from before the last write is used.
+pub async fn get_templates_from(fp_file: &str, keys: &StorageKeys) -> Result<Templates, Error> {
+    let _lock = match lock_templates(fp_file, LockKind::Shared).await {
+        // There can't be any templates if the directory doesn't exist
+        Err(lock_templates::Error::Open(e)) if e.kind() == ErrorKind::NotFound => {
+            return Ok(Default::default())
+        }
+        result => result.map_err(Error::Lock)?,
+    };
+    get_templates_while_locked(fp_file, keys).await
+}
+
+/// Like [`get_templates_from`], but the caller must already hold a lock on the file
+pub(crate) async fn get_templates_while_locked(
+    fp_file: &str,
+    keys: &StorageKeys,
+) -> Result<Templates, Error> {
+    match read_templates(fp_file, keys).await {
+        Err(error @ (Error::Decrypt(_) | Error::Decode(_))) => {
+            let backup_fp_file = get_backup_fp_file(fp_file);
+            warn!("Error reading {fp_file}: {error}. Using backup.");
+            match read_templates(&backup_fp_file, keys).await {
+                Ok(Some(templates)) => Ok(templates),
+                Ok(None) => Err(Error::Corrupted {
+                    error: Box::new(error),
+                    backup_error: Box::new(Error::Open(io::Error::from(ErrorKind::NotFound))),
+                }),
+                Err(backup_error) => Err(Error::Corrupted {
+                    error: Box::new(error),
+                    backup_error: Box::new(backup_error),
+                }),
+            }
+        }
+        result => Ok(result?.unwrap_or_default()),
+    }
+}
+
+/// Returns `None` if the file doesn't exist
+async fn read_templates(fp_file: &str, keys: &StorageKeys) -> Result<Option<Templates>, Error> {
+    match OpenOptions::new().read(true).open(fp_file).await {
         Ok(mut file) => {
             let mut buf = Default::default();
-            file.read_to_end(&mut buf)
-                .await
-                .map_err(Error::Read)?;
-            let templates =
-                rmp_serde::from_slice::<Templates>(&buf).map_err( Error::Decode)?;
-            Ok(templates)
+            file.read_to_end(&mut buf).await.map_err(Error::Read)?;
+            Ok(Some(decode_templates(
+                &keys.decrypt(&buf).map_err(Error::Decrypt)?,
+            )?))
         }
         Err(e) => match e.kind() {
-            ErrorKind::NotFound => Ok(Default::default()),
+            ErrorKind::NotFound => Ok(None),
             _ => Err(Error::Open(e)),
         },
     }
 }
+
+/// Decodes an unencrypted templates file.
+/// Files without the magic are from before the format was versioned, and are converted.
+pub fn decode_templates(buf: &[u8]) -> Result<Templates, Error> {
+    match buf.strip_prefix(&MAGIC) {
+        Some(buf) => {
+            let (version, buf) = buf.split_first_chunk::<4>().ok_or_else(|| {
+                Error::Decode(decode::Error::Syntax("Missing format version".into()))
+            })?;
+            match u32::from_le_bytes(*version) {
+                FORMAT_VERSION => rmp_serde::from_slice(buf).map_err(Error::Decode),
+                version => Err(Error::UnsupportedVersion(version)),
+            }
+        }
+        None => Ok(from_legacy(
+            rmp_serde::from_slice::<LegacyTemplates>(buf).map_err(Error::Decode)?,
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_std::task::block_on;
+
+    use super::*;
+    use crate::set_templates::set_templates_to;
+    use crate::test_dir::TestDir;
+    use crate::test_templates::{random_keys, tamper, templates};
+
+    #[test]
+    fn backup_is_used_if_decoding_fails() {
+        let dir = TestDir::new("backup-decode");
+        let fp_file = dir.file("templates");
+        let keys = StorageKeys::default();
+        block_on(async {
+            set_templates_to(dir.path(), &fp_file, &templates(&["old"]), &keys)
+                .await
+                .unwrap();
+            set_templates_to(dir.path(), &fp_file, &templates(&["new"]), &keys)
+                .await
+                .unwrap();
+            // Like a file that was only partially written
+            let contents = std::fs::read(&fp_file).unwrap();
+            std::fs::write(&fp_file, &contents[..contents.len() / 2]).unwrap();
+            let templates = get_templates_from(&fp_file, &keys).await.unwrap();
+            assert!(templates.contains_key("old"));
+        });
+    }
+
+    #[test]
+    fn missing_file_has_no_templates() {
+        let dir = TestDir::new("missing");
+        block_on(async {
+            let templates = get_templates_from(&dir.file("templates"), &random_keys())
+                .await
+                .unwrap();
+            assert!(templates.is_empty());
+        });
+    }
+
+    #[test]
+    fn backup_is_used_if_decrypting_fails() {
+        let dir = TestDir::new("backup-decrypt");
+        let fp_file = dir.file("templates");
+        let keys = random_keys();
+        block_on(async {
+            set_templates_to(dir.path(), &fp_file, &templates(&["old"]), &keys)
+                .await
+                .unwrap();
+            set_templates_to(dir.path(), &fp_file, &templates(&["new"]), &keys)
+                .await
+                .unwrap();
+            tamper(&fp_file);
+            let templates = get_templates_from(&fp_file, &keys).await.unwrap();
+            assert!(templates.contains_key("old"));
+        });
+    }
+
+    #[test]
+    fn backup_is_used_if_the_key_is_wrong() {
+        let dir = TestDir::new("backup-key");
+        let fp_file = dir.file("templates");
+        let old_keys = random_keys();
+        let new_keys = random_keys();
+        block_on(async {
+            set_templates_to(dir.path(), &fp_file, &templates(&["old"]), &new_keys)
+                .await
+                .unwrap();
+            set_templates_to(dir.path(), &fp_file, &templates(&["new"]), &old_keys)
+                .await
+                .unwrap();
+            let templates = get_templates_from(&fp_file, &new_keys).await.unwrap();
+            assert!(templates.contains_key("old"));
+        });
+    }
+
+    #[test]
+    fn corrupted_without_backup() {
+        let dir = TestDir::new("no-backup");
+        let fp_file = dir.file("templates");
+        let keys = random_keys();
+        block_on(async {
+            set_templates_to(dir.path(), &fp_file, &templates(&["new"]), &keys)
+                .await
+                .unwrap();
+            tamper(&fp_file);
+            assert!(matches!(
+                get_templates_from(&fp_file, &keys).await,
+                Err(Error::Corrupted { .. })
+            ));
+        });
+    }
+}
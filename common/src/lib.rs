@@ -1,8 +1,24 @@
 #![warn(unused_crate_dependencies)]
 
+pub mod caller;
+pub mod config;
+pub mod encryption;
 pub mod enroll_step_dbus_result;
 pub mod fp_file;
 pub mod get_templates;
+pub mod lock_templates;
+pub mod manager_dbus;
+pub mod polkit;
+pub mod rust_fp2_dbus;
 pub mod rust_fp_dbus;
 pub mod set_templates;
+pub mod shared_driver;
 pub mod template;
+pub mod template_store;
+#[cfg(test)]
+mod test_bus;
+#[cfg(test)]
+mod test_dir;
+#[cfg(test)]
+mod test_templates;
+pub mod update_templates;
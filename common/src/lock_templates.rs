@@ -0,0 +1,146 @@
+use std::fmt::{Display, Formatter};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::time::{Duration, Instant};
+
+use async_std::task::sleep;
+use nix::errno::Errno;
+use nix::fcntl::{Flock, FlockArg};
+
+use crate::fp_file::get_lock_fp_file;
+
+/// How long to wait for another process to finish using a templates file
+pub const LOCK_TIMEOUT: Duration = Duration::from_secs(5);
+const RETRY_INTERVAL: Duration = Duration::from_millis(50);
+
+#[derive(Debug)]
+pub enum Error {
+    Open(io::Error),
+    Lock(Errno),
+    /// Something else kept the templates file locked for longer than [`LOCK_TIMEOUT`]
+    Timeout,
+}
+
+impl std::error::Error for Error {}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Open(e) => {
+                write!(f, "Error opening lock file: {:#?}", e)
+            }
+            Self::Lock(e) => {
+                write!(f, "Error locking file: {:#?}", e)
+            }
+            Self::Timeout => {
+                write!(
+                    f,
+                    "Templates file is still in use after waiting {:?}",
+                    LOCK_TIMEOUT
+                )
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockKind {
+    /// Lets other readers use the file at the same time
+    Shared,
+    /// Needed to write the file
+    Exclusive,
+}
+
+/// An advisory lock on a templates file. It is released when this is dropped.
+pub struct TemplatesLock {
+    _lock: Flock<File>,
+}
+
+/// Waits until nothing else is using the templates file, or gives up after [`LOCK_TIMEOUT`].
+/// The directory of the templates file must exist.
+pub async fn lock_templates(fp_file: &str, kind: LockKind) -> Result<TemplatesLock, Error> {
+    lock_templates_within(fp_file, kind, LOCK_TIMEOUT).await
+}
+
+/// Like [`lock_templates`], but gives up after `timeout`
+async fn lock_templates_within(
+    fp_file: &str,
+    kind: LockKind,
+    timeout: Duration,
+) -> Result<TemplatesLock, Error> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(get_lock_fp_file(fp_file))
+        .map_err(Error::Open)?;
+    let arg = match kind {
+        LockKind::Shared => FlockArg::LockSharedNonblock,
+        LockKind::Exclusive => FlockArg::LockExclusiveNonblock,
+    };
+    let start = Instant::now();
+    loop {
+        // Don't block, so that the executor isn't blocked and so that the wait is bounded
+        file = match Flock::lock(file, arg) {
+            Ok(lock) => return Ok(TemplatesLock { _lock: lock }),
+            Err((file, Errno::EWOULDBLOCK)) => file,
+            Err((_file, e)) => return Err(Error::Lock(e)),
+        };
+        if start.elapsed() >= timeout {
+            return Err(Error::Timeout);
+        }
+        sleep(RETRY_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_std::task::{block_on, spawn};
+
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    const SHORT_TIMEOUT: Duration = Duration::from_millis(200);
+
+    #[test]
+    fn shared_locks_can_be_held_at_the_same_time() {
+        let dir = TestDir::new("lock-shared");
+        let fp_file = dir.file("templates");
+        block_on(async {
+            let _lock = lock_templates(&fp_file, LockKind::Shared).await.unwrap();
+            lock_templates_within(&fp_file, LockKind::Shared, SHORT_TIMEOUT)
+                .await
+                .unwrap();
+        });
+    }
+
+    #[test]
+    fn exclusive_lock_times_out() {
+        let dir = TestDir::new("lock-timeout");
+        let fp_file = dir.file("templates");
+        block_on(async {
+            let _lock = lock_templates(&fp_file, LockKind::Shared).await.unwrap();
+            let start = Instant::now();
+            assert!(matches!(
+                lock_templates_within(&fp_file, LockKind::Exclusive, SHORT_TIMEOUT).await,
+                Err(Error::Timeout)
+            ));
+            assert!(start.elapsed() >= SHORT_TIMEOUT);
+        });
+    }
+
+    #[test]
+    fn lock_is_taken_once_it_is_released() {
+        let dir = TestDir::new("lock-released");
+        let fp_file = dir.file("templates");
+        block_on(async {
+            let lock = lock_templates(&fp_file, LockKind::Exclusive).await.unwrap();
+            spawn(async move {
+                sleep(SHORT_TIMEOUT).await;
+                drop(lock);
+            });
+            lock_templates(&fp_file, LockKind::Exclusive).await.unwrap();
+        });
+    }
+}
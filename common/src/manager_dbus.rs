@@ -0,0 +1,66 @@
+use serde::{Deserialize, Serialize};
+use zbus::zvariant::{OwnedObjectPath, Type};
+use zbus::{fdo, interface};
+
+use crate::shared_driver::SharedDriver;
+
+/// Where the manager is served. The default device is also served here, for clients from before there were multiple devices.
+/// The default device is the first one that was available when the daemon started.
+pub const MANAGER_PATH: &str = "/org/rust_fp/RustFp";
+
+/// Where the `org.rust_fp.RustFp` and `org.rust_fp.RustFp2` interfaces of a device are served
+pub fn get_device_path(index: usize) -> String {
+    format!("{MANAGER_PATH}/Device{index}")
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Type)]
+pub struct DeviceEntry {
+    pub path: OwnedObjectPath,
+    /// The name of the device's driver
+    pub name: String,
+    /// If the sensor is plugged in. Using a device that isn't available fails right away.
+    pub available: bool,
+}
+
+/// Lists the fingerprint sensors that the daemon can use. Every driver has a device, even if its sensor isn't plugged in.
+pub struct Manager {
+    pub devices: Vec<(OwnedObjectPath, SharedDriver)>,
+    /// The index of the device that is also served at [`MANAGER_PATH`]
+    pub default_device: usize,
+}
+
+#[interface(
+    name = "org.rust_fp.Manager",
+    proxy(
+        default_path = "/org/rust_fp/RustFp",
+        default_service = "org.rust_fp.RustFp"
+    )
+)]
+impl Manager {
+    async fn get_devices(&self) -> Vec<DeviceEntry> {
+        self.devices
+            .iter()
+            .map(|(path, driver)| DeviceEntry {
+                path: path.clone(),
+                name: driver.name().into(),
+                available: driver.is_available(),
+            })
+            .collect()
+    }
+
+    async fn get_default_device(&self) -> fdo::Result<OwnedObjectPath> {
+        self.devices
+            .get(self.default_device)
+            .map(|(path, _driver)| path.clone())
+            .ok_or_else(|| fdo::Error::Failed("No devices available".into()))
+    }
+
+    /// Finds a device by its name
+    async fn get_device(&self, name: &str) -> fdo::Result<OwnedObjectPath> {
+        self.devices
+            .iter()
+            .find(|(_path, driver)| driver.name() == name)
+            .map(|(path, _driver)| path.clone())
+            .ok_or_else(|| fdo::Error::InvalidArgs(format!("No device named {name:?}")))
+    }
+}
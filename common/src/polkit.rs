@@ -0,0 +1,241 @@
+use std::collections::HashMap;
+
+use log::info;
+use serde::{Deserialize, Serialize};
+use zbus::message::Header;
+use zbus::zvariant::{Type, Value};
+use zbus::{fdo, proxy, Connection};
+
+/// Lets polkit ask the user to authenticate, if an authentication agent is running
+const ALLOW_USER_INTERACTION: u32 = 1;
+
+/// The actions in `org.rust_fp.policy`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Enroll fingers for yourself
+    EnrollOwn,
+    /// Enroll fingers for another user
+    EnrollAny,
+    Verify,
+    Delete,
+    /// Re-encrypt every user's templates with a new key
+    Rekey,
+    /// Get raw images from the sensor, which can include someone's fingerprint
+    Capture,
+    /// Cancel another user's enroll or match
+    CancelAny,
+    /// Replace the legacy seed, which deletes the templates that need it
+    NewSeed,
+    /// Enroll or match with raw templates that aren't stored by the daemon, which bypasses its storage and checks
+    RawTemplates,
+}
+
+impl Action {
+    pub fn id(&self) -> &'static str {
+        match self {
+            Self::EnrollOwn => "org.rust_fp.enroll-own",
+            Self::EnrollAny => "org.rust_fp.enroll-any",
+            Self::Verify => "org.rust_fp.verify",
+            Self::Delete => "org.rust_fp.delete",
+            Self::Rekey => "org.rust_fp.rekey",
+            Self::Capture => "org.rust_fp.capture",
+            Self::CancelAny => "org.rust_fp.cancel-any",
+            Self::NewSeed => "org.rust_fp.new-seed",
+            Self::RawTemplates => "org.rust_fp.raw-templates",
+        }
+    }
+}
+
+/// Who is asking to do the action
+#[derive(Debug, Serialize, Type)]
+struct Subject<'a> {
+    kind: &'a str,
+    details: HashMap<&'a str, Value<'a>>,
+}
+
+#[derive(Debug, Deserialize, Type)]
+struct AuthorizationResult {
+    is_authorized: bool,
+    is_challenge: bool,
+    // Only used for logging
+    #[allow(dead_code)]
+    details: HashMap<String, String>,
+}
+
+#[proxy(
+    interface = "org.freedesktop.PolicyKit1.Authority",
+    default_service = "org.freedesktop.PolicyKit1",
+    default_path = "/org/freedesktop/PolicyKit1/Authority"
+)]
+trait Authority {
+    fn check_authorization(
+        &self,
+        subject: &Subject<'_>,
+        action_id: &str,
+        details: HashMap<&str, &str>,
+        flags: u32,
+        cancellation_id: &str,
+    ) -> zbus::Result<AuthorizationResult>;
+}
+
+/// Asks polkit if the sender of a message can do the action.
+/// The authority is looked up on the same bus that the message came from, so a stand-in authority on a private bus can be used.
+pub async fn check_authorization(
+    connection: &Connection,
+    header: &Header<'_>,
+    action: Action,
+) -> fdo::Result<()> {
+    let sender = header
+        .sender()
+        .ok_or_else(|| fdo::Error::Failed("Unknown sender".into()))?;
+    let subject = Subject {
+        kind: "system-bus-name",
+        details: HashMap::from([("name", Value::from(sender.as_str()))]),
+    };
+    let result = AuthorityProxy::new(connection)
+        .await?
+        .check_authorization(
+            &subject,
+            action.id(),
+            Default::default(),
+            ALLOW_USER_INTERACTION,
+            "",
+        )
+        .await
+        .map_err(|e| fdo::Error::Failed(format!("Error checking authorization: {e}")))?;
+    info!("Authorization of {sender} for {}: {result:?}", action.id());
+    match (result.is_authorized, result.is_challenge) {
+        (true, _) => Ok(()),
+        (false, true) => Err(fdo::Error::AccessDenied(format!(
+            "Authentication is required for {}, but no authentication agent is running",
+            action.id()
+        ))),
+        (false, false) => Err(fdo::Error::AccessDenied(format!(
+            "Not authorized for {}",
+            action.id()
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use async_std::task::block_on;
+    use zbus::connection::Builder;
+    use zbus::interface;
+
+    use super::*;
+    use crate::test_bus::{start_bus, StubAuthority};
+
+    const ACTIONS: [Action; 9] = [
+        Action::EnrollOwn,
+        Action::EnrollAny,
+        Action::Verify,
+        Action::Delete,
+        Action::Rekey,
+        Action::Capture,
+        Action::CancelAny,
+        Action::NewSeed,
+        Action::RawTemplates,
+    ];
+
+    /// Checks authorization for whoever calls it, like the daemon's methods do
+    struct Checker;
+
+    #[interface(name = "org.rust_fp.Test")]
+    impl Checker {
+        async fn check(
+            &self,
+            action_id: &str,
+            #[zbus(header)] header: Header<'_>,
+            #[zbus(connection)] connection: &Connection,
+        ) -> fdo::Result<()> {
+            let action = ACTIONS
+                .into_iter()
+                .find(|action| action.id() == action_id)
+                .unwrap();
+            check_authorization(connection, &header, action).await
+        }
+    }
+
+    /// Checks every action with a stub authority, and returns the error names, or `None` if it was authorized
+    fn check_every_action(name: &str, authorized: bool, challenge: bool) -> Vec<Option<String>> {
+        let bus = start_bus(name);
+        let subjects = Arc::new(Mutex::new(Vec::new()));
+        block_on(async {
+            let daemon = Builder::address(bus.address.as_str())
+                .unwrap()
+                .name("org.freedesktop.PolicyKit1")
+                .unwrap()
+                .serve_at(
+                    "/org/freedesktop/PolicyKit1/Authority",
+                    StubAuthority {
+                        authorized,
+                        challenge,
+                        subjects: subjects.clone(),
+                    },
+                )
+                .unwrap()
+                .serve_at("/org/rust_fp/Test", Checker)
+                .unwrap()
+                .build()
+                .await
+                .unwrap();
+            let client = Builder::address(bus.address.as_str())
+                .unwrap()
+                .build()
+                .await
+                .unwrap();
+            let mut errors = Vec::new();
+            for action in ACTIONS {
+                let result = client
+                    .call_method(
+                        daemon.unique_name(),
+                        "/org/rust_fp/Test",
+                        Some("org.rust_fp.Test"),
+                        "Check",
+                        &action.id(),
+                    )
+                    .await;
+                errors.push(match result {
+                    Ok(_) => None,
+                    Err(zbus::Error::MethodError(name, _, _)) => Some(name.to_string()),
+                    Err(e) => panic!("Error calling Check: {e}"),
+                });
+            }
+            // The authority is asked about the client, not the daemon
+            let client_name = client.unique_name().unwrap().to_string();
+            assert_eq!(
+                *subjects.lock().unwrap(),
+                ACTIONS
+                    .iter()
+                    .map(|action| (client_name.clone(), action.id().to_owned()))
+                    .collect::<Vec<_>>()
+            );
+            errors
+        })
+    }
+
+    fn access_denied() -> Vec<Option<String>> {
+        vec![Some("org.freedesktop.DBus.Error.AccessDenied".into()); ACTIONS.len()]
+    }
+
+    #[test]
+    fn authorized() {
+        let errors = check_every_action("polkit-authorized", true, false);
+        assert_eq!(errors, vec![None; ACTIONS.len()]);
+    }
+
+    #[test]
+    fn not_authorized() {
+        let errors = check_every_action("polkit-denied", false, false);
+        assert_eq!(errors, access_denied());
+    }
+
+    #[test]
+    fn challenge_without_agent() {
+        let errors = check_every_action("polkit-challenge", false, true);
+        assert_eq!(errors, access_denied());
+    }
+}
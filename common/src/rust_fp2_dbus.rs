@@ -0,0 +1,975 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use log::info;
+use rust_fp::fingerprint_driver::{
+    self, EnrollStepError, EnrollStepOutput, MatchOutput, NoMatchError,
+};
+use rust_fp::key_provider::{KeyProvider, LEGACY_SEED};
+use serde::{Deserialize, Serialize};
+use zbus::message::Header;
+use zbus::zvariant::{OwnedValue, Type, Value};
+use zbus::{fdo, interface, Connection, SignalContext};
+
+use crate::caller::{get_caller, get_caller_uid, get_target_user, get_user};
+use crate::enroll_step_dbus_result::EnrollStepDbusOutput;
+use crate::polkit::{check_authorization, Action};
+use crate::rust_fp_dbus::{driver_error, RustFp};
+use crate::template::TemplateEntry;
+use crate::template_store::TemplateStore;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Type)]
+#[zvariant(signature = "s")]
+#[serde(rename_all = "kebab-case")]
+pub enum EnrollStepStatus {
+    InProgress,
+    Complete,
+    LowQuality,
+    /// The finger should be moved a bit before touching again
+    Immobile,
+    Cancelled,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Type)]
+pub struct EnrollStep {
+    /// Pass this id to the next enroll step to continue the enroll session
+    pub id: u32,
+    pub status: EnrollStepStatus,
+    pub percentage: u8,
+    /// The enrolled template if the status is `complete`, otherwise empty
+    pub template: Vec<u8>,
+    /// What went wrong if the status is `failed`, otherwise empty
+    pub error: String,
+}
+
+impl From<EnrollStepDbusOutput> for EnrollStep {
+    fn from(output: EnrollStepDbusOutput) -> Self {
+        let error = match &output.result {
+            Err(EnrollStepError::GenericError) => "The sensor couldn't enroll the touch".into(),
+            Err(EnrollStepError::Driver(e)) => e.to_string(),
+            _ => String::new(),
+        };
+        let (status, percentage, template) = match output.result {
+            Ok(EnrollStepOutput::InProgress(percentage)) => {
+                (EnrollStepStatus::InProgress, percentage, Vec::new())
+            }
+            Ok(EnrollStepOutput::Complete(template)) => (EnrollStepStatus::Complete, 100, template),
+            Err(EnrollStepError::LowQuality) => (EnrollStepStatus::LowQuality, 0, Vec::new()),
+            Err(EnrollStepError::Immobile) => (EnrollStepStatus::Immobile, 0, Vec::new()),
+            Err(EnrollStepError::Cancelled) => (EnrollStepStatus::Cancelled, 0, Vec::new()),
+            Err(EnrollStepError::GenericError | EnrollStepError::Driver(_)) => {
+                (EnrollStepStatus::Failed, 0, Vec::new())
+            }
+        };
+        Self {
+            id: output.id,
+            status,
+            percentage,
+            template,
+            error,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Type)]
+#[zvariant(signature = "s")]
+#[serde(rename_all = "kebab-case")]
+pub enum MatchStatus {
+    Match,
+    NoMatch,
+    LowQuality,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Type)]
+pub struct MatchResult {
+    pub status: MatchStatus,
+    /// The index of the matched template if the status is `match`
+    pub index: u32,
+    /// The matched template, if it was updated. Otherwise empty.
+    pub updated_template: Vec<u8>,
+}
+
+impl From<MatchOutput> for MatchResult {
+    fn from(output: MatchOutput) -> Self {
+        let no_match = |status| Self {
+            status,
+            index: 0,
+            updated_template: Vec::new(),
+        };
+        match output {
+            MatchOutput::Match(matched) => Self {
+                status: MatchStatus::Match,
+                index: matched.index as u32,
+                updated_template: matched.updated_template.unwrap_or_default(),
+            },
+            MatchOutput::NoMatch(None) => no_match(MatchStatus::NoMatch),
+            MatchOutput::NoMatch(Some(NoMatchError::LowQuality)) => {
+                no_match(MatchStatus::LowQuality)
+            }
+            MatchOutput::NoMatch(Some(NoMatchError::Other)) => no_match(MatchStatus::Failed),
+        }
+    }
+}
+
+/// A finger and its template's metadata
+#[derive(Debug, Clone, Serialize, Deserialize, Type)]
+pub struct FingerInfo {
+    pub label: String,
+    /// The template's id as hex. It changes when the sensor updates the template.
+    pub id: String,
+    /// The driver that enrolled the template. Empty if unknown.
+    pub driver: String,
+    /// Empty if unknown
+    pub sensor_id: String,
+    /// Empty if unknown
+    pub hardware_version: String,
+    /// The sensor's firmware when the finger was enrolled. Empty if unknown.
+    pub firmware_version: String,
+    /// Unix time in seconds. 0 if unknown.
+    pub created: u64,
+    /// Unix time in seconds. 0 if the finger never matched, or if it's unknown.
+    pub last_matched: u64,
+    pub match_count: u64,
+    pub update_count: u64,
+    /// The finger was enrolled by a different driver or sensor than the one being used, so it probably won't match
+    pub other_sensor: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Type)]
+#[zvariant(signature = "s")]
+#[serde(rename_all = "kebab-case")]
+pub enum ScanType {
+    Press,
+    Swipe,
+}
+
+impl From<fingerprint_driver::ScanType> for ScanType {
+    fn from(scan_type: fingerprint_driver::ScanType) -> Self {
+        match scan_type {
+            fingerprint_driver::ScanType::Press => Self::Press,
+            fingerprint_driver::ScanType::Swipe => Self::Swipe,
+        }
+    }
+}
+
+/// What `CaptureImage` captures
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Type)]
+#[zvariant(signature = "s")]
+#[serde(rename_all = "kebab-case")]
+pub enum CaptureType {
+    /// The finger that is put on the sensor
+    Simple,
+    /// A checkerboard test pattern, without a finger
+    Pattern,
+    QualityTest,
+    /// The sensor right after resetting it, without a finger
+    ResetTest,
+}
+
+impl From<CaptureType> for fingerprint_driver::CaptureType {
+    fn from(capture_type: CaptureType) -> Self {
+        match capture_type {
+            CaptureType::Simple => Self::Simple,
+            CaptureType::Pattern => Self::Pattern,
+            CaptureType::QualityTest => Self::QualityTest,
+            CaptureType::ResetTest => Self::ResetTest,
+        }
+    }
+}
+
+/// A raw image from the sensor. Pixels are row by row, and pixels with more than 8 bits are little endian.
+#[derive(Debug, Clone, Serialize, Deserialize, Type)]
+pub struct Image {
+    pub width: u32,
+    pub height: u32,
+    pub bits_per_pixel: u8,
+    pub pixels: Vec<u8>,
+}
+
+impl From<fingerprint_driver::Image> for Image {
+    fn from(image: fingerprint_driver::Image) -> Self {
+        Self {
+            width: image.width,
+            height: image.height,
+            bits_per_pixel: image.bits_per_pixel,
+            pixels: image.pixels,
+        }
+    }
+}
+
+/// Low level information about the sensor, for debugging
+#[derive(Debug, Clone, Serialize, Deserialize, Type, Value, OwnedValue)]
+pub struct DeviceInfo {
+    pub vendor_id: u32,
+    pub product_id: u32,
+    pub model_id: u32,
+    pub version: u32,
+    pub width: u32,
+    pub height: u32,
+    pub bits_per_pixel: u8,
+    pub frame_size: u32,
+    pub template_size: u32,
+    pub template_version: u32,
+    pub max_templates: u32,
+    /// The number of templates that are loaded on the sensor
+    pub loaded_templates: u32,
+    /// A bit for every loaded template that was updated by matching and not downloaded yet
+    pub dirty_templates: u32,
+    /// A bit for every version of the host command protocol that the sensor supports
+    pub protocol_versions: u32,
+    pub max_request_size: u32,
+    pub max_response_size: u32,
+    pub seed_set: bool,
+}
+
+impl From<fingerprint_driver::DeviceInfo> for DeviceInfo {
+    fn from(info: fingerprint_driver::DeviceInfo) -> Self {
+        Self {
+            vendor_id: info.vendor_id,
+            product_id: info.product_id,
+            model_id: info.model_id,
+            version: info.version,
+            width: info.width,
+            height: info.height,
+            bits_per_pixel: info.bits_per_pixel,
+            frame_size: info.frame_size,
+            template_size: info.template_size,
+            template_version: info.template_version,
+            max_templates: info.max_templates,
+            loaded_templates: info.loaded_templates,
+            dirty_templates: info.dirty_templates,
+            protocol_versions: info.protocol_versions,
+            max_request_size: info.max_request_size,
+            max_response_size: info.max_response_size,
+            seed_set: info.seed_set,
+        }
+    }
+}
+
+/// One thing that `SelfTest` checked
+#[derive(Debug, Clone, Serialize, Deserialize, Type)]
+pub struct SelfTestCheck {
+    pub name: String,
+    pub passed: bool,
+    pub details: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Type)]
+pub struct SelfTestReport {
+    pub driver: String,
+    pub sensor_id: String,
+    pub hardware_version: String,
+    /// Empty if unknown
+    pub firmware_version: String,
+    /// If every check passed
+    pub passed: bool,
+    pub checks: Vec<SelfTestCheck>,
+}
+
+/// What the sensor can do, so that frontends can adapt their UI
+#[derive(Debug, Clone, Serialize, Deserialize, Type)]
+pub struct SensorCapabilities {
+    /// If the sensor is plugged in. Everything else is empty if it isn't.
+    pub available: bool,
+    /// Templates are matched by the sensor instead of by the computer
+    pub on_chip_matching: bool,
+    pub max_templates: u32,
+    /// 0 if templates don't all have the same size
+    pub template_size: u32,
+    pub image_capture: bool,
+    /// The sensor can detect fingers without matching them
+    pub finger_detect: bool,
+    /// Matching can update the matched template
+    pub template_update_on_match: bool,
+    pub scan_type: ScanType,
+    pub enroll_stages: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Type)]
+pub struct VerifyResult {
+    pub status: MatchStatus,
+    /// The label of the matched finger if the status is `match`
+    pub label: String,
+}
+
+/// Version 2 of the `RustFp` interface, which uses D-Bus types instead of postcard.
+/// It works with any D-Bus client, not just Rust programs built with the same `rust-fp` types.
+/// Signals and the properties from version 1 are on `org.rust_fp.RustFp`, which is served on the same object.
+pub struct RustFp2 {
+    pub rust_fp: RustFp,
+    pub store: TemplateStore,
+    pub key_provider: Arc<dyn KeyProvider>,
+}
+
+impl RustFp2 {
+    /// The labels and templates of a user's fingers that were enrolled by this device
+    async fn verifiable_templates(&self, uid: u32) -> fdo::Result<(Vec<String>, Vec<Vec<u8>>)> {
+        let driver = self.rust_fp.driver();
+        // Templates enrolled on other sensors can't match, and they could be usable on another device
+        Ok(self
+            .store
+            .get_templates(uid)
+            .await?
+            .into_iter()
+            .filter(|(label, entry)| {
+                let other_sensor = entry.is_from_other_sensor(driver.name(), &driver.sensor_id());
+                if other_sensor {
+                    info!(
+                        "Skipping template {label:?} of uid {uid}, which was enrolled by a different sensor: {:?}",
+                        entry.metadata
+                    );
+                }
+                !other_sensor
+            })
+            .map(|(label, entry)| (label, entry.template))
+            .unzip())
+    }
+}
+
+#[interface(
+    name = "org.rust_fp.RustFp2",
+    proxy(
+        default_path = "/org/rust_fp/RustFp",
+        default_service = "org.rust_fp.RustFp"
+    )
+)]
+impl RustFp2 {
+    async fn get_max_templates(&self) -> fdo::Result<u32> {
+        let max_templates = self
+            .rust_fp
+            .driver()
+            .lock()
+            .await
+            .get_max_templates()
+            .map_err(|e| fdo::Error::Failed(format!("Error getting max templates: {e}")))?;
+        Ok(max_templates as u32)
+    }
+
+    async fn get_capabilities(&self) -> SensorCapabilities {
+        let driver = self.rust_fp.driver();
+        let capabilities = driver.capabilities();
+        SensorCapabilities {
+            available: driver.is_available(),
+            on_chip_matching: capabilities.on_chip_matching,
+            max_templates: capabilities.max_templates as u32,
+            template_size: capabilities.template_size.unwrap_or_default() as u32,
+            image_capture: capabilities.image_capture,
+            finger_detect: capabilities.finger_detect,
+            template_update_on_match: capabilities.template_update_on_match,
+            scan_type: capabilities.scan_type.into(),
+            enroll_stages: capabilities.enroll_stages,
+        }
+    }
+
+    /// Pass 0 as the id to start a new enroll session. The template is returned instead of being stored,
+    /// so this needs authorization for `org.rust_fp.raw-templates`.
+    async fn enroll_step(
+        &self,
+        id: u32,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(signal_context)] ctxt: SignalContext<'_>,
+    ) -> fdo::Result<EnrollStep> {
+        check_authorization(connection, &header, Action::RawTemplates).await?;
+        let caller = get_caller(connection, &header).await?;
+        Ok(self
+            .rust_fp
+            .enroll_step_output(id, caller.uid.as_raw(), caller, connection, &ctxt)
+            .await?
+            .into())
+    }
+
+    /// Matches against templates that aren't stored by the daemon. Needs authorization for `org.rust_fp.raw-templates`.
+    async fn match_templates(
+        &self,
+        templates: Vec<Vec<u8>>,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(signal_context)] ctxt: SignalContext<'_>,
+    ) -> fdo::Result<MatchResult> {
+        check_authorization(connection, &header, Action::RawTemplates).await?;
+        let caller = get_caller(connection, &header).await?;
+        Ok(self
+            .rust_fp
+            .match_templates_output(caller.uid.as_raw(), &templates, &caller, &ctxt)
+            .await?
+            .into())
+    }
+
+    /// Does an enroll step for a user. Pass an empty username for the caller. Once enrolling is complete,
+    /// the template is saved by the daemon with the label, and it is not returned.
+    async fn enroll_finger(
+        &self,
+        username: &str,
+        id: u32,
+        label: &str,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(signal_context)] ctxt: SignalContext<'_>,
+    ) -> fdo::Result<EnrollStep> {
+        let caller = get_caller(connection, &header).await?;
+        let uid = get_user(caller.uid, username)?.uid;
+        let action = match uid == caller.uid {
+            true => Action::EnrollOwn,
+            false => Action::EnrollAny,
+        };
+        check_authorization(connection, &header, action).await?;
+        let uid = uid.as_raw();
+        if self.store.get_templates(uid).await?.contains_key(label) {
+            return Err(fdo::Error::InvalidArgs(
+                "A fingerprint with that label already exists".into(),
+            ));
+        }
+        let mut step = EnrollStep::from(
+            self.rust_fp
+                .enroll_step_output(id, uid, caller, connection, &ctxt)
+                .await?,
+        );
+        if step.status == EnrollStepStatus::Complete {
+            let driver = self.rust_fp.driver();
+            let template = TemplateEntry::new(
+                std::mem::take(&mut step.template),
+                driver.name(),
+                &driver.sensor_id(),
+                driver.uses_legacy_seed(),
+            );
+            self.store
+                .update_templates(uid, |templates| {
+                    templates.insert(label.to_owned(), template);
+                })
+                .await?;
+            info!("Saved template {label:?} for uid {uid}");
+        }
+        Ok(step)
+    }
+
+    /// Lists the labels of the caller's fingers
+    async fn list_fingers(
+        &self,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(connection)] connection: &Connection,
+    ) -> fdo::Result<Vec<String>> {
+        let uid = get_caller_uid(connection, &header).await?.as_raw();
+        Ok(self.store.get_templates(uid).await?.into_keys().collect())
+    }
+
+    /// Lists the caller's fingers with information about their templates
+    async fn list_fingers_info(
+        &self,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(connection)] connection: &Connection,
+    ) -> fdo::Result<Vec<FingerInfo>> {
+        let uid = get_caller_uid(connection, &header).await?.as_raw();
+        let driver = self.rust_fp.driver();
+        Ok(self
+            .store
+            .get_templates(uid)
+            .await?
+            .into_iter()
+            .map(|(label, entry)| {
+                let other_sensor = entry.is_from_other_sensor(driver.name(), &driver.sensor_id());
+                let id = entry.id().to_string();
+                let metadata = entry.metadata;
+                let sensor = metadata.sensor.unwrap_or_default();
+                FingerInfo {
+                    label,
+                    id,
+                    driver: metadata.driver.unwrap_or_default(),
+                    sensor_id: sensor.id,
+                    hardware_version: sensor.hardware_version,
+                    firmware_version: sensor.firmware_version,
+                    created: metadata.created.unwrap_or_default(),
+                    last_matched: metadata.last_matched.unwrap_or_default(),
+                    match_count: metadata.match_count,
+                    update_count: metadata.update_count,
+                    other_sensor,
+                }
+            })
+            .collect())
+    }
+
+    async fn delete_finger(
+        &self,
+        label: &str,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(connection)] connection: &Connection,
+    ) -> fdo::Result<()> {
+        check_authorization(connection, &header, Action::Delete).await?;
+        let uid = get_caller_uid(connection, &header).await?.as_raw();
+        self.store
+            .update_templates(uid, |templates| templates.remove(label))
+            .await?
+            .ok_or_else(|| fdo::Error::InvalidArgs(format!("Finger {label:?} doesn't exist")))?;
+        Ok(())
+    }
+
+    /// Deletes the finger whose template has the id, as hex from [`FingerInfo::id`]
+    async fn delete_finger_by_id(
+        &self,
+        id: &str,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(connection)] connection: &Connection,
+    ) -> fdo::Result<String> {
+        check_authorization(connection, &header, Action::Delete).await?;
+        let uid = get_caller_uid(connection, &header).await?.as_raw();
+        self.store
+            .update_templates(uid, |templates| {
+                let label = templates
+                    .iter()
+                    .find(|(_, entry)| entry.id().to_string() == id.to_lowercase())
+                    .map(|(label, _)| label.clone())?;
+                templates.remove(&label);
+                Some(label)
+            })
+            .await?
+            .ok_or_else(|| fdo::Error::InvalidArgs(format!("No finger has the id {id:?}")))
+    }
+
+    async fn delete_all_fingers(
+        &self,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(connection)] connection: &Connection,
+    ) -> fdo::Result<()> {
+        check_authorization(connection, &header, Action::Delete).await?;
+        let uid = get_caller_uid(connection, &header).await?.as_raw();
+        self.store
+            .update_templates(uid, |templates| templates.clear())
+            .await?;
+        Ok(())
+    }
+
+    /// Adds templates which were stored outside of the daemon, such as in `~/.var/cros-fp-templates`.
+    /// Templates with labels that already exist are skipped. Returns the labels that were added.
+    async fn import_fingers(
+        &self,
+        templates: HashMap<String, Vec<u8>>,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(connection)] connection: &Connection,
+    ) -> fdo::Result<Vec<String>> {
+        check_authorization(connection, &header, Action::EnrollOwn).await?;
+        let uid = get_caller_uid(connection, &header).await?.as_raw();
+        Ok(self
+            .store
+            .update_templates(uid, |existing_templates| {
+                templates
+                    .into_iter()
+                    .filter_map(
+                        |(label, template)| match existing_templates.contains_key(&label) {
+                            true => None,
+                            false => {
+                                existing_templates
+                                    .insert(label.clone(), TemplateEntry::legacy(template));
+                                Some(label)
+                            }
+                        },
+                    )
+                    .collect()
+            })
+            .await?)
+    }
+
+    /// If a user has fingers that can be verified on this device, so that clients can skip verifying if they don't.
+    /// Pass an empty username for the caller. Only root can check other users.
+    async fn has_fingers(
+        &self,
+        username: &str,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(connection)] connection: &Connection,
+    ) -> fdo::Result<bool> {
+        check_authorization(connection, &header, Action::Verify).await?;
+        let uid = get_target_user(connection, &header, username)
+            .await?
+            .uid
+            .as_raw();
+        Ok(!self.verifiable_templates(uid).await?.0.is_empty())
+    }
+
+    /// Matches a finger against a user's fingers. Pass an empty username for the caller.
+    /// Only root can verify other users.
+    async fn verify(
+        &self,
+        username: &str,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(signal_context)] ctxt: SignalContext<'_>,
+    ) -> fdo::Result<VerifyResult> {
+        check_authorization(connection, &header, Action::Verify).await?;
+        let uid = get_target_user(connection, &header, username)
+            .await?
+            .uid
+            .as_raw();
+        let (labels, templates) = self.verifiable_templates(uid).await?;
+        if templates.is_empty() {
+            return Err(fdo::Error::Failed(
+                "No fingers are enrolled on this device".into(),
+            ));
+        }
+        let caller = get_caller(connection, &header).await?;
+        let result = MatchResult::from(
+            self.rust_fp
+                .match_templates_output(uid, &templates, &caller, &ctxt)
+                .await?,
+        );
+        let label = match result.status {
+            MatchStatus::Match => labels[result.index as usize].clone(),
+            _ => String::new(),
+        };
+        if result.status == MatchStatus::Match {
+            let updated_template =
+                Some(result.updated_template).filter(|template| !template.is_empty());
+            self.store
+                .update_templates(uid, |templates| {
+                    if let Some(entry) = templates.get_mut(&label) {
+                        entry.record_match(updated_template);
+                    }
+                })
+                .await?;
+        }
+        Ok(VerifyResult {
+            status: result.status,
+            label,
+        })
+    }
+
+    /// Waits until a finger is on the sensor, without matching it.
+    /// Fails with `NotSupported` if the sensor can't do this, see `GetCapabilities`.
+    async fn wait_finger_down(
+        &self,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(signal_context)] ctxt: SignalContext<'_>,
+    ) -> fdo::Result<()> {
+        check_authorization(connection, &header, Action::Verify).await?;
+        let caller = get_caller(connection, &header).await?;
+        self.rust_fp.wait_finger_output(true, &caller, &ctxt).await
+    }
+
+    /// Waits until there is no finger on the sensor, for example between enroll steps.
+    /// Fails with `NotSupported` if the sensor can't do this, see `GetCapabilities`.
+    async fn wait_finger_up(
+        &self,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(signal_context)] ctxt: SignalContext<'_>,
+    ) -> fdo::Result<()> {
+        check_authorization(connection, &header, Action::Verify).await?;
+        let caller = get_caller(connection, &header).await?;
+        self.rust_fp.wait_finger_output(false, &caller, &ctxt).await
+    }
+
+    /// Captures a raw image for diagnosing the sensor. Waits for a finger if the capture type needs one.
+    /// Fails with `NotSupported` if the sensor can't do this, see `GetCapabilities`.
+    async fn capture_image(
+        &self,
+        capture_type: CaptureType,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(connection)] connection: &Connection,
+    ) -> fdo::Result<Image> {
+        check_authorization(connection, &header, Action::Capture).await?;
+        let caller = get_caller(connection, &header).await?;
+        let driver = self.rust_fp.driver();
+        driver.check_available().map_err(driver_error)?;
+        // Capturing would end the enroll session on the sensor
+        let image = driver
+            .lock_unless_enrolling(&caller)
+            .await
+            .map_err(driver_error)?
+            .capture_image(capture_type.into())
+            .await
+            .map_err(driver_error)?;
+        info!(
+            "Captured {capture_type:?} image: {}x{}, {} bits per pixel",
+            image.width, image.height, image.bits_per_pixel
+        );
+        Ok(image.into())
+    }
+
+    /// Tests the sensor's hardware and firmware, so that problems can be found without other tools
+    async fn self_test(
+        &self,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(connection)] connection: &Connection,
+    ) -> fdo::Result<SelfTestReport> {
+        check_authorization(connection, &header, Action::Verify).await?;
+        let caller = get_caller(connection, &header).await?;
+        let driver = self.rust_fp.driver();
+        driver.check_available().map_err(driver_error)?;
+        // Testing would end the enroll session on the sensor
+        let report = driver
+            .lock_unless_enrolling(&caller)
+            .await
+            .map_err(driver_error)?
+            .self_test()
+            .await
+            .map_err(driver_error)?;
+        info!("Self test: {report:?}");
+        Ok(SelfTestReport {
+            driver: driver.name().into(),
+            passed: report.passed(),
+            sensor_id: report.sensor.id,
+            hardware_version: report.sensor.hardware_version,
+            firmware_version: report.sensor.firmware_version,
+            checks: report
+                .checks
+                .into_iter()
+                .map(|check| SelfTestCheck {
+                    name: check.name,
+                    passed: check.passed,
+                    details: check.details,
+                })
+                .collect(),
+        })
+    }
+
+    /// Saves every user's templates again with a new encryption key, and deletes the old key.
+    /// If encryption is turned off, templates are saved unencrypted instead.
+    async fn rekey(
+        &self,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(connection)] connection: &Connection,
+    ) -> fdo::Result<()> {
+        check_authorization(connection, &header, Action::Rekey).await?;
+        self.store.rekey().await?;
+        Ok(())
+    }
+
+    /// Stops using the legacy seed, by saving a new random seed for sensors to encrypt templates with.
+    /// Templates that only work with the legacy seed are deleted from every user. Other templates are kept.
+    /// Sensors only use the new seed after rebooting. Fails if the legacy seed isn't being used.
+    async fn new_seed(
+        &self,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(connection)] connection: &Connection,
+    ) -> fdo::Result<()> {
+        check_authorization(connection, &header, Action::NewSeed).await?;
+        if self.key_provider.get_seed().await.map_err(driver_error)? != LEGACY_SEED {
+            return Err(fdo::Error::Failed(
+                "The legacy seed isn't used, so there is no need for a new seed".into(),
+            ));
+        }
+        self.key_provider
+            .replace_seed()
+            .await
+            .map_err(driver_error)?;
+        self.store
+            .update_every_user(|templates| {
+                templates.retain(|_label, entry| !entry.needs_legacy_seed())
+            })
+            .await?;
+        info!("Replaced the legacy seed and deleted templates that need it");
+        Ok(())
+    }
+
+    /// Ends an enroll session early, so that something else can enroll. Only the client that started it can end it.
+    /// Enroll sessions also end if they have no enroll steps for a minute, or if the client disconnects.
+    async fn abort_enroll(
+        &self,
+        id: u32,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(signal_context)] ctxt: SignalContext<'_>,
+    ) -> fdo::Result<()> {
+        let caller = get_caller(connection, &header).await?;
+        self.rust_fp
+            .end_enroll_session(id, Some(&caller), &ctxt)
+            .await
+    }
+
+    /// Stops waiting for a finger. Cancelling another user's call needs authorization.
+    async fn cancel(
+        &self,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(connection)] connection: &Connection,
+    ) -> fdo::Result<()> {
+        self.rust_fp.cancel_for(connection, &header).await
+    }
+
+    /// Fails with `NotSupported` if the driver doesn't have this information
+    #[zbus(property(emits_changed_signal = "false"))]
+    async fn device_info(&self) -> fdo::Result<DeviceInfo> {
+        let driver = self.rust_fp.driver();
+        driver.check_available().map_err(driver_error)?;
+        let info = driver.lock().await.device_info().map_err(driver_error)?;
+        Ok(info.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use async_std::task::block_on;
+    use nix::unistd::{getuid, Uid};
+    use rust_fp::key_provider::FileKeyProvider;
+    use zbus::connection::Builder;
+    use zbus::names::OwnedUniqueName;
+    use zbus::zvariant::DynamicType;
+
+    use super::*;
+    use crate::caller::Caller;
+    use crate::shared_driver::SharedDriver;
+    use crate::test_bus::{start_bus, Bus, StubAuthority};
+    use crate::test_dir::TestDir;
+
+    /// The interface on a private bus, with an unavailable sensor and an authority that denies everything
+    struct Daemon {
+        driver: SharedDriver,
+        subjects: Arc<Mutex<Vec<(String, String)>>>,
+        daemon: Connection,
+        client: Connection,
+        _bus: Bus,
+        _dir: TestDir,
+    }
+
+    impl Daemon {
+        async fn start(name: &str) -> Self {
+            let bus = start_bus(&format!("{name}-bus"));
+            let dir = TestDir::new(name);
+            let driver = SharedDriver::new("Test", None);
+            let subjects = Arc::new(Mutex::new(Vec::new()));
+            let rust_fp2 = RustFp2 {
+                rust_fp: RustFp::new(driver.clone()),
+                store: TemplateStore::open(dir.path(), &dir.file("storage-key"), false)
+                    .await
+                    .unwrap(),
+                key_provider: Arc::new(FileKeyProvider {
+                    path: dir.file("seed").into(),
+                    legacy: false,
+                }),
+            };
+            let daemon = Builder::address(bus.address.as_str())
+                .unwrap()
+                .name("org.freedesktop.PolicyKit1")
+                .unwrap()
+                .serve_at(
+                    "/org/freedesktop/PolicyKit1/Authority",
+                    StubAuthority {
+                        authorized: false,
+                        challenge: false,
+                        subjects: subjects.clone(),
+                    },
+                )
+                .unwrap()
+                .serve_at("/org/rust_fp/RustFp", rust_fp2)
+                .unwrap()
+                .build()
+                .await
+                .unwrap();
+            let client = Builder::address(bus.address.as_str())
+                .unwrap()
+                .build()
+                .await
+                .unwrap();
+            Self {
+                driver,
+                subjects,
+                daemon,
+                client,
+                _bus: bus,
+                _dir: dir,
+            }
+        }
+
+        /// Returns the error name, or `None` if the call succeeded
+        async fn call(
+            &self,
+            method: &str,
+            body: &(impl Serialize + DynamicType),
+        ) -> Option<String> {
+            match self
+                .client
+                .call_method(
+                    self.daemon.unique_name(),
+                    "/org/rust_fp/RustFp",
+                    Some("org.rust_fp.RustFp2"),
+                    method,
+                    body,
+                )
+                .await
+            {
+                Ok(_) => None,
+                Err(zbus::Error::MethodError(name, _, _)) => Some(name.to_string()),
+                Err(e) => panic!("Error calling {method}: {e}"),
+            }
+        }
+
+        /// The actions that the authority was asked about
+        fn actions(&self) -> Vec<String> {
+            self.subjects
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(_name, action)| action.clone())
+                .collect()
+        }
+    }
+
+    const ACCESS_DENIED: &str = "org.freedesktop.DBus.Error.AccessDenied";
+
+    #[test]
+    fn enrolling_for_yourself_asks_enroll_own() {
+        block_on(async {
+            let daemon = Daemon::start("interface-enroll-own").await;
+            let error = daemon.call("EnrollFinger", &("", 0u32, "finger")).await;
+            assert_eq!(error.as_deref(), Some(ACCESS_DENIED));
+            assert_eq!(daemon.actions(), ["org.rust_fp.enroll-own"]);
+        });
+    }
+
+    #[test]
+    fn enrolling_for_another_user_asks_enroll_any() {
+        block_on(async {
+            let daemon = Daemon::start("interface-enroll-any").await;
+            let other_user = match getuid().is_root() {
+                true => "nobody",
+                false => "root",
+            };
+            let error = daemon
+                .call("EnrollFinger", &(other_user, 0u32, "finger"))
+                .await;
+            assert_eq!(error.as_deref(), Some(ACCESS_DENIED));
+            assert_eq!(daemon.actions(), ["org.rust_fp.enroll-any"]);
+        });
+    }
+
+    #[test]
+    fn verifying_asks_verify() {
+        block_on(async {
+            let daemon = Daemon::start("interface-verify").await;
+            let error = daemon.call("Verify", &("",)).await;
+            assert_eq!(error.as_deref(), Some(ACCESS_DENIED));
+            assert_eq!(daemon.actions(), ["org.rust_fp.verify"]);
+        });
+    }
+
+    #[test]
+    fn cancelling_another_users_call_asks_cancel_any() {
+        block_on(async {
+            let daemon = Daemon::start("interface-cancel-any").await;
+            let other = Caller {
+                name: OwnedUniqueName::try_from(":1.1000").unwrap(),
+                uid: Uid::from_raw(getuid().as_raw().wrapping_add(1)),
+            };
+            let _guard = daemon.driver.lock_for(&other).await;
+            let error = daemon.call("Cancel", &()).await;
+            assert_eq!(error.as_deref(), Some(ACCESS_DENIED));
+            assert_eq!(daemon.actions(), ["org.rust_fp.cancel-any"]);
+        });
+    }
+
+    #[test]
+    fn cancelling_your_own_call_doesnt_ask() {
+        block_on(async {
+            let daemon = Daemon::start("interface-cancel-own").await;
+            let own = Caller {
+                name: OwnedUniqueName::try_from(":1.1000").unwrap(),
+                uid: getuid(),
+            };
+            let _guard = daemon.driver.lock_for(&own).await;
+            assert_eq!(daemon.call("Cancel", &()).await, None);
+            assert!(daemon.actions().is_empty());
+        });
+    }
+}
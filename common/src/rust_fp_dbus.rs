@@ -1,18 +1,377 @@
+use std::future::Future;
+use std::pin::pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use async_std::sync::Mutex;
+use async_std::task::{sleep, spawn};
+use log::debug;
 use log::info;
 use log::warn;
 use postcard::to_allocvec;
-use rand::random;
-use rust_fp::fingerprint_driver::EnrollStepOutput;
-use rust_fp::fingerprint_driver::OpenedFingerprintDriver;
-use zbus::{fdo, interface};
+use rust_fp::fingerprint_driver::{
+    DriverError, EnrollStepError, EnrollStepOutput, MatchOutput, NoMatchError,
+};
+use zbus::export::futures_util::future::{pending, select, Either};
+use zbus::message::Header;
+use zbus::{fdo, interface, Connection, SignalContext};
 
+use crate::caller::{get_caller, wait_until_disconnected, Caller};
 use crate::enroll_step_dbus_result::EnrollStepDbusOutput;
+use crate::polkit::{check_authorization, Action};
+use crate::shared_driver::{EnrollSession, SharedDriver};
+
+/// How long an enroll session can go without an enroll step before it is ended, so that a client that stopped
+/// enrolling without ending the session doesn't lock everyone else out of enrolling
+const ENROLL_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// What the sensor is being used for through this interface
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Idle,
+    Enrolling,
+    Matching,
+}
 
+/// Clones share the same state, so that other interfaces can be served next to this one
+#[derive(Clone)]
 pub struct RustFp {
-    pub driver: Box<dyn OpenedFingerprintDriver>,
-    pub enrolling_id: Option<u32>,
+    driver: SharedDriver,
+    mode: Arc<Mutex<Mode>>,
+}
+
+impl RustFp {
+    pub fn new(driver: SharedDriver) -> Self {
+        Self {
+            driver,
+            mode: Arc::new(Mutex::new(Mode::Idle)),
+        }
+    }
+
+    pub(crate) fn driver(&self) -> &SharedDriver {
+        &self.driver
+    }
+
+    async fn set_mode(&self, ctxt: &SignalContext<'_>, mode: Mode) {
+        *self.mode.lock().await = mode;
+        log_signal_error(self.mode_changed(ctxt).await);
+    }
+
+    /// Runs an enroll step or match, emitting [`Self::finger_down`] as soon as the driver notices the finger.
+    /// Also returns if it was emitted, so that it isn't emitted again once the result is known.
+    async fn emit_finger_down_while<T>(
+        &self,
+        ctxt: &SignalContext<'_>,
+        operation: impl Future<Output = T>,
+    ) -> (T, bool) {
+        let Some(listener) = self.driver.finger_down_listener() else {
+            return (operation.await, false);
+        };
+        listener.clear();
+        let mut operation = pin!(operation);
+        let finger_down = pin!(listener.wait());
+        match select(operation.as_mut(), finger_down).await {
+            Either::Left((output, _)) => (output, false),
+            Either::Right(((), _)) => {
+                log_signal_error(Self::finger_down(ctxt).await);
+                (operation.await, true)
+            }
+        }
+    }
+
+    /// Does an enroll step for `user`, for any interface that exposes enrolling.
+    /// Only the `owner` that started the enroll session can continue it, and the session is ended if it disconnects from the bus.
+    pub(crate) async fn enroll_step_output(
+        &self,
+        id: u32,
+        user: u32,
+        owner: Caller,
+        connection: &Connection,
+        ctxt: &SignalContext<'_>,
+    ) -> fdo::Result<EnrollStepDbusOutput> {
+        self.driver.check_available().map_err(driver_error)?;
+        let (id, started) = {
+            let mut enroll_session = self.driver.enroll_session().await;
+            match enroll_session.as_mut() {
+                None => Ok((
+                    enroll_session
+                        .insert(EnrollSession::new(owner.clone(), user))
+                        .id,
+                    true,
+                )),
+                Some(session) if session.id != id => Err(driver_error(DriverError::Busy)),
+                Some(session) if session.owner != owner => Err(fdo::Error::AccessDenied(
+                    "The enroll session was started by another client".into(),
+                )),
+                Some(session) if session.user != user => Err(fdo::Error::InvalidArgs(
+                    "The enroll session is for another user".into(),
+                )),
+                // An enroll step is already waiting for a finger
+                Some(EnrollSession { stepping: true, .. }) => Err(driver_error(DriverError::Busy)),
+                Some(session) => {
+                    session.stepping = true;
+                    Ok((id, false))
+                }
+            }
+        }?;
+        if started {
+            spawn(self.clone().watch_enroll_session(
+                id,
+                owner.clone(),
+                connection.clone(),
+                ctxt.to_owned(),
+            ));
+            log_signal_error(self.enrolling_changed(ctxt).await);
+        }
+        self.set_mode(ctxt, Mode::Enrolling).await;
+        let (result, finger_down_emitted) = {
+            let mut driver = self.driver.lock_for(&owner).await;
+            self.emit_finger_down_while(ctxt, driver.start_or_continue_enroll(user))
+                .await
+        };
+        self.set_mode(ctxt, Mode::Idle).await;
+        self.emit_device_reset_if_detected(ctxt).await;
+        let touched = matches!(
+            result,
+            Ok(_) | Err(EnrollStepError::LowQuality | EnrollStepError::Immobile)
+        );
+        if touched && !finger_down_emitted {
+            log_signal_error(Self::finger_down(ctxt).await);
+        }
+        match &result {
+            Ok(output) => {
+                let percentage = match output {
+                    EnrollStepOutput::InProgress(percentage) => *percentage,
+                    EnrollStepOutput::Complete(_) => 100,
+                };
+                log_signal_error(Self::enroll_progress(ctxt, percentage).await);
+            }
+            Err(EnrollStepError::LowQuality) => {
+                log_signal_error(Self::low_quality(ctxt).await);
+            }
+            Err(_) => {}
+        }
+        let ended = matches!(
+            result,
+            Ok(EnrollStepOutput::Complete(_)) | Err(EnrollStepError::Cancelled)
+        );
+        {
+            let mut enroll_session = self.driver.enroll_session().await;
+            // The session may have been ended while the step was running
+            let session = enroll_session.as_mut().filter(|session| session.id == id);
+            match ended {
+                true => {
+                    enroll_session.take_if(|session| session.id == id);
+                }
+                false => {
+                    if let Some(session) = session {
+                        session.stepping = false;
+                        session.last_activity = Instant::now();
+                    }
+                }
+            }
+        }
+        if ended {
+            log_signal_error(self.enrolling_changed(ctxt).await);
+        }
+        info!("Enroll id: {id}. Result: {result:?}.");
+        Ok(EnrollStepDbusOutput { id, result })
+    }
+
+    /// Ends the enroll session with the id, if it is still active.
+    /// If there is an `owner`, the session is only ended if it started it.
+    pub(crate) async fn end_enroll_session(
+        &self,
+        id: u32,
+        owner: Option<&Caller>,
+        ctxt: &SignalContext<'_>,
+    ) -> fdo::Result<()> {
+        match self.driver.enroll_session().await.as_ref() {
+            Some(session)
+                if session.id == id && owner.is_some_and(|owner| *owner != session.owner) =>
+            {
+                return Err(fdo::Error::AccessDenied(
+                    "The enroll session was started by another client".into(),
+                ));
+            }
+            Some(session) if session.id == id => {}
+            _ => {
+                return Err(fdo::Error::InvalidArgs(
+                    "There is no enroll session with that id".into(),
+                ))
+            }
+        }
+        if !self.driver.end_enroll_session(id).await {
+            return Ok(());
+        }
+        info!("Ended enroll session {id}");
+        log_signal_error(self.enrolling_changed(ctxt).await);
+        Ok(())
+    }
+
+    /// Ends the enroll session if it times out or if its owner disconnects from the bus
+    async fn watch_enroll_session(
+        self,
+        id: u32,
+        owner: Caller,
+        connection: Connection,
+        ctxt: SignalContext<'static>,
+    ) {
+        let owner = owner.name;
+        let disconnected = async {
+            if let Err(e) = wait_until_disconnected(&connection, &owner).await {
+                // The timeout still ends the session
+                warn!("Error watching enroll session owner {owner}: {e:?}");
+                pending::<()>().await;
+            }
+            info!("Enroll session owner {owner} disconnected");
+        };
+        let timed_out = async {
+            loop {
+                let deadline = match self.driver.enroll_session().await.as_ref() {
+                    Some(session) if session.id == id => match session.stepping {
+                        true => Instant::now() + ENROLL_TIMEOUT,
+                        false => session.last_activity + ENROLL_TIMEOUT,
+                    },
+                    // The session already ended
+                    _ => return,
+                };
+                match deadline.checked_duration_since(Instant::now()) {
+                    Some(remaining) if !remaining.is_zero() => sleep(remaining).await,
+                    _ => {
+                        info!("Enroll session {id} timed out");
+                        return;
+                    }
+                }
+            }
+        };
+        select(pin!(disconnected), pin!(timed_out)).await;
+        // The session may have ended already
+        let _ = self.end_enroll_session(id, None, &ctxt).await;
+    }
+
+    /// Matches templates for the `caller`, for any interface that exposes matching
+    pub(crate) async fn match_templates_output(
+        &self,
+        user: u32,
+        templates: &[Vec<u8>],
+        caller: &Caller,
+        ctxt: &SignalContext<'_>,
+    ) -> fdo::Result<MatchOutput> {
+        self.driver.check_available().map_err(driver_error)?;
+        debug!("Matching");
+        self.set_mode(ctxt, Mode::Matching).await;
+        let mut finger_down_emitted = false;
+        let output = loop {
+            // Matching would end the enroll session on the sensor
+            let mut driver = match self.driver.lock_unless_enrolling(caller).await {
+                Ok(driver) => driver,
+                Err(e) => break Err(e),
+            };
+            let (output, emitted) = self
+                .emit_finger_down_while(ctxt, driver.match_templates(user, templates))
+                .await;
+            finger_down_emitted |= emitted;
+            match output {
+                // Matching again waits until the sensor is ready after waking up, so the client doesn't notice
+                Err(DriverError::Cancelled) if self.driver.is_sleeping() => {}
+                output => break output,
+            }
+        }
+        .map_err(driver_error);
+        self.set_mode(ctxt, Mode::Idle).await;
+        self.emit_device_reset_if_detected(ctxt).await;
+        let output = output?;
+        debug!("Got match output");
+        if !finger_down_emitted {
+            log_signal_error(Self::finger_down(ctxt).await);
+        }
+        if let MatchOutput::NoMatch(Some(NoMatchError::LowQuality)) = output {
+            log_signal_error(Self::low_quality(ctxt).await);
+        }
+        log_signal_error(Self::match_result(ctxt, matches!(output, MatchOutput::Match(_))).await);
+        Ok(output)
+    }
+
+    /// Waits for a finger to be put on (`down`) or taken off the sensor, for any interface that exposes finger detection
+    pub(crate) async fn wait_finger_output(
+        &self,
+        down: bool,
+        caller: &Caller,
+        ctxt: &SignalContext<'_>,
+    ) -> fdo::Result<()> {
+        self.driver.check_available().map_err(driver_error)?;
+        if !self.driver.capabilities().finger_detect {
+            return Err(fdo::Error::NotSupported(
+                "The sensor can't detect fingers without matching them".into(),
+            ));
+        }
+        let result = {
+            let mut driver = self.driver.lock_for(caller).await;
+            match down {
+                true => driver.wait_finger_down().await,
+                false => driver.wait_finger_up().await,
+            }
+        };
+        result.map_err(driver_error)?;
+        log_signal_error(match down {
+            true => Self::finger_down(ctxt).await,
+            false => Self::finger_up(ctxt).await,
+        });
+        Ok(())
+    }
+
+    /// Cancels what the driver is doing, for any interface that exposes cancelling.
+    /// Clients can cancel what they or other clients of the same user are doing.
+    /// Cancelling anything else needs authorization for [`Action::CancelAny`].
+    pub(crate) async fn cancel_for(
+        &self,
+        connection: &Connection,
+        header: &Header<'_>,
+    ) -> fdo::Result<()> {
+        let caller = get_caller(connection, header).await?;
+        match self.driver.owner() {
+            Some(owner) if owner.name == caller.name || owner.uid == caller.uid => {}
+            // There is nothing to cancel
+            None if !self.driver.is_busy() => return Ok(()),
+            _ => check_authorization(connection, header, Action::CancelAny).await?,
+        }
+        info!("{} is cancelling", caller.name);
+        self.driver.cancel();
+        Ok(())
+    }
+
+    /// Lets observers know if the driver found out that the sensor reset, which happens during suspend
+    async fn emit_device_reset_if_detected(&self, ctxt: &SignalContext<'_>) {
+        if self.driver.lock().await.take_reset_detected() {
+            info!("Fingerprint sensor was reset");
+            log_signal_error(Self::device_reset(ctxt).await);
+        }
+    }
 }
 
+/// Errors from the driver are sent to clients as text.
+/// A busy sensor is `LimitsExceeded`, so that clients can tell that trying again later could work.
+/// Too many templates is `LimitsExceeded` too, since giving fewer templates could work.
+pub(crate) fn driver_error(e: DriverError) -> fdo::Error {
+    match e {
+        DriverError::Unsupported => fdo::Error::NotSupported(format!("{e}")),
+        DriverError::Timeout => fdo::Error::TimedOut(format!("{e}")),
+        DriverError::Busy | DriverError::TooManyTemplates { .. } => {
+            fdo::Error::LimitsExceeded(format!("{e}"))
+        }
+        e => fdo::Error::Failed(format!("{e}")),
+    }
+}
+
+/// Signals are just for observers, so failing to emit them shouldn't fail the method call
+fn log_signal_error(result: zbus::Result<()>) {
+    if let Err(e) = result {
+        warn!("Error emitting signal: {e:?}");
+    }
+}
+
+// Methods take `&self` so that `cancel` can be called while `enroll_step` or `match_templates` is waiting for a finger
 #[interface(
     name = "org.rust_fp.RustFp",
     proxy(
@@ -21,49 +380,113 @@ pub struct RustFp {
     )
 )]
 impl RustFp {
-    async fn get_max_templates(&mut self) -> fdo::Result<u64> {
+    async fn get_max_templates(&self) -> fdo::Result<u64> {
         let max_templates = self
             .driver
+            .lock()
+            .await
             .get_max_templates()
-            .map_err(|_e| fdo::Error::Failed("Error getting max templates".into()))?;
+            .map_err(|e| fdo::Error::Failed(format!("Error getting max templates: {e}")))?;
         Ok(max_templates as u64)
     }
 
-    async fn enroll_step(&mut self, id: u32) -> fdo::Result<Vec<u8>> {
-        let id = match self.enrolling_id {
-            None => {
-                let id = random();
-                self.enrolling_id = Some(id);
-                Ok(id)
-            }
-            Some(enrolling_id) => {
-                if enrolling_id != id {
-                    // TODO: Have a timeout or something so a faulty app doesn't disable the FP sensor until this program is restarted
-                    Err(fdo::Error::Failed(
-                        "Something else is in the middle of enrolling. Wait until it's done."
-                            .into(),
-                    ))
-                } else {
-                    Ok(id)
-                }
-            }
-        }?;
-        let result = self.driver.start_or_continue_enroll().await;
-        if let Ok(EnrollStepOutput::Complete(_)) = result {
-            self.enrolling_id = None;
-        }
-        info!("Enroll id: {id}. Result: {result:?}.");
-        Ok(to_allocvec(&EnrollStepDbusOutput { id, result }).unwrap())
+    async fn enroll_step(
+        &self,
+        id: u32,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(signal_context)] ctxt: SignalContext<'_>,
+    ) -> fdo::Result<Vec<u8>> {
+        check_authorization(connection, &header, Action::RawTemplates).await?;
+        let caller = get_caller(connection, &header).await?;
+        let output = self
+            .enroll_step_output(id, caller.uid.as_raw(), caller, connection, &ctxt)
+            .await?;
+        Ok(to_allocvec(&output).unwrap())
     }
 
-    async fn match_templates(&mut self, templates: Vec<Vec<u8>>) -> fdo::Result<Vec<u8>> {
-        warn!("Matching");
+    async fn match_templates(
+        &self,
+        templates: Vec<Vec<u8>>,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(signal_context)] ctxt: SignalContext<'_>,
+    ) -> fdo::Result<Vec<u8>> {
+        check_authorization(connection, &header, Action::RawTemplates).await?;
+        let caller = get_caller(connection, &header).await?;
         let output = self
-            .driver
-            .match_templates(&templates)
-            .await
-            .map_err(|e| fdo::Error::Failed(format!("{e:?}")))?;
-        warn!("Got match output");
+            .match_templates_output(caller.uid.as_raw(), &templates, &caller, &ctxt)
+            .await?;
         Ok(to_allocvec(&output).unwrap())
     }
+
+    /// Stops waiting for a finger in the current `enroll_step`, `match_templates`, `WaitFinger*` or `CaptureImage` call.
+    /// A cancelled enroll step returns the `Cancelled` error, and the enroll session ends.
+    /// Cancelling another user's call needs authorization.
+    async fn cancel(
+        &self,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(connection)] connection: &Connection,
+    ) -> fdo::Result<()> {
+        self.cancel_for(connection, &header).await
+    }
+
+    /// Ends the enroll session with the id, so that something else can enroll. Only the client that started it can end it.
+    /// If an enroll step is waiting for a finger, it returns the `Cancelled` error.
+    async fn abort_enroll(
+        &self,
+        id: u32,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(signal_context)] ctxt: SignalContext<'_>,
+    ) -> fdo::Result<()> {
+        let caller = get_caller(connection, &header).await?;
+        self.end_enroll_session(id, Some(&caller), &ctxt).await
+    }
+
+    /// A finger was placed on the sensor while enrolling or matching, or a `WaitFingerDown` call found a finger.
+    /// It is emitted as soon as the sensor notices the finger, or with the result if the sensor can't tell earlier.
+    #[zbus(signal)]
+    async fn finger_down(ctxt: &SignalContext<'_>) -> zbus::Result<()>;
+
+    /// A finger was lifted off the sensor. Only emitted with drivers that can detect this.
+    #[zbus(signal)]
+    async fn finger_up(ctxt: &SignalContext<'_>) -> zbus::Result<()>;
+
+    /// An enroll step succeeded. The session's id isn't sent, since only its client should know it.
+    #[zbus(signal)]
+    async fn enroll_progress(ctxt: &SignalContext<'_>, percentage: u8) -> zbus::Result<()>;
+
+    /// The scan was not good enough, and the finger should be placed again
+    #[zbus(signal)]
+    async fn low_quality(ctxt: &SignalContext<'_>) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    async fn match_result(ctxt: &SignalContext<'_>, matched: bool) -> zbus::Result<()>;
+
+    /// The sensor reset itself and lost the templates loaded on it, which usually happens during suspend
+    #[zbus(signal)]
+    async fn device_reset(ctxt: &SignalContext<'_>) -> zbus::Result<()>;
+
+    /// `Idle`, `Enrolling` or `Matching`
+    #[zbus(property)]
+    async fn mode(&self) -> String {
+        format!("{:?}", *self.mode.lock().await)
+    }
+
+    /// If an enroll session is active. Nothing else can enroll until it is complete.
+    #[zbus(property)]
+    async fn enrolling(&self) -> bool {
+        self.driver.is_enrolling().await
+    }
+
+    #[zbus(property(emits_changed_signal = "const"))]
+    async fn max_templates(&self) -> fdo::Result<u64> {
+        self.get_max_templates().await
+    }
+
+    #[zbus(property(emits_changed_signal = "const"))]
+    async fn driver_name(&self) -> String {
+        self.driver.name().into()
+    }
 }
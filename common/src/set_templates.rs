@@ -1,22 +1,29 @@
 use std::fmt::{Display, Formatter};
 use std::io;
 
-use async_std::fs::{create_dir_all, OpenOptions};
+use async_std::fs::{create_dir_all, hard_link, remove_file, rename, File, OpenOptions};
 use async_std::io::WriteExt;
 use rmp_serde::encode;
 
+use crate::encryption::{self, StorageKeys};
 use crate::fp_file;
-use crate::fp_file::{get_fp_dir, get_fp_file};
-use crate::template::Templates;
+use crate::fp_file::{get_backup_fp_file, get_fp_dir, get_fp_file, get_temp_fp_file};
+use crate::lock_templates::{self, lock_templates, LockKind};
+use crate::template::{Templates, FORMAT_VERSION, MAGIC};
 
 #[derive(Debug)]
 pub enum Error {
     Encode(encode::Error),
+    Encrypt(encryption::Error),
     FpDir(fp_file::Error),
     CreateDir(io::Error),
     FpFile(fp_file::Error),
+    Lock(lock_templates::Error),
     Open(io::Error),
     Write(io::Error),
+    Sync(io::Error),
+    Backup(io::Error),
+    Rename(io::Error),
 }
 
 impl std::error::Error for Error {}
@@ -27,6 +34,9 @@ impl Display for Error {
             Self::Encode(e) => {
                 write!(f, "Error encoding file: {:#?}", e)
             }
+            Self::Encrypt(e) => {
+                write!(f, "Error encrypting file: {}", e)
+            }
             Self::FpDir(e) => {
                 write!(f, "Error getting fp file: {:#?}", e)
             }
@@ -36,27 +46,154 @@ impl Display for Error {
             Self::FpFile(e) => {
                 write!(f, "Error getting fp file: {:#?}", e)
             }
+            Self::Lock(e) => {
+                write!(f, "Error locking file: {}", e)
+            }
             Self::Open(e) => {
                 write!(f, "Error opening file: {:#?}", e)
             }
             Self::Write(e) => {
-                write!(f, "Error reading file: {:#?}", e)
+                write!(f, "Error writing file: {:#?}", e)
+            }
+            Self::Sync(e) => {
+                write!(f, "Error syncing file: {:#?}", e)
+            }
+            Self::Backup(e) => {
+                write!(f, "Error backing up file: {:#?}", e)
+            }
+            Self::Rename(e) => {
+                write!(f, "Error renaming file: {:#?}", e)
             }
         }
     }
 }
 
-pub async fn set_templates(templates: &Templates) -> Result<(), Error> {
-    let vec = encode::to_vec(templates).map_err(Error::Encode)?;
-    create_dir_all(get_fp_dir().map_err(Error::FpDir)?)
+/// Templates in a user's own file are never encrypted
+pub async fn set_templates(username: &str, templates: &Templates) -> Result<(), Error> {
+    set_templates_to(
+        &get_fp_dir(username).map_err(Error::FpDir)?,
+        &get_fp_file(username).map_err(Error::FpFile)?,
+        templates,
+        &StorageKeys::default(),
+    )
+    .await
+}
+
+/// Writes templates to a specific file instead of a user's own file.
+/// The file is replaced atomically, so it's never partially written, even if we crash.
+/// The previous file is kept as a backup.
+/// The file is encrypted with the current key, if there is one.
+pub async fn set_templates_to(
+    fp_dir: &str,
+    fp_file: &str,
+    templates: &Templates,
+    keys: &StorageKeys,
+) -> Result<(), Error> {
+    create_dir_all(fp_dir).await.map_err(Error::CreateDir)?;
+    let _lock = lock_templates(fp_file, LockKind::Exclusive)
         .await
-        .map_err(Error::CreateDir)?;
+        .map_err(Error::Lock)?;
+    set_templates_while_locked(fp_dir, fp_file, templates, keys).await
+}
+
+/// Like [`set_templates_to`], but the caller must already hold an exclusive lock on the file
+pub(crate) async fn set_templates_while_locked(
+    fp_dir: &str,
+    fp_file: &str,
+    templates: &Templates,
+    keys: &StorageKeys,
+) -> Result<(), Error> {
+    let vec = keys
+        .encrypt(
+            [
+                &MAGIC[..],
+                &FORMAT_VERSION.to_le_bytes(),
+                &encode::to_vec(templates).map_err(Error::Encode)?,
+            ]
+            .concat(),
+        )
+        .map_err(Error::Encrypt)?;
+    let temp_fp_file = get_temp_fp_file(fp_file);
     let mut file = OpenOptions::new()
         .write(true)
         .create(true)
-        .open(get_fp_file().map_err(Error::FpFile)?)
+        .truncate(true)
+        .open(&temp_fp_file)
         .await
         .map_err(Error::Open)?;
-    file.write(&vec).await.map_err(Error::Write)?;
+    file.write_all(&vec).await.map_err(Error::Write)?;
+    file.sync_all().await.map_err(Error::Sync)?;
+    // The backup is a hard link, so the templates file is always there
+    let backup_fp_file = get_backup_fp_file(fp_file);
+    match remove_file(&backup_fp_file).await {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(Error::Backup(e)),
+        _ => Ok(()),
+    }?;
+    match hard_link(fp_file, &backup_fp_file).await {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(Error::Backup(e)),
+        _ => Ok(()),
+    }?;
+    rename(&temp_fp_file, fp_file)
+        .await
+        .map_err(Error::Rename)?;
+    // Make sure the rename is saved
+    File::open(fp_dir)
+        .await
+        .map_err(Error::Open)?
+        .sync_all()
+        .await
+        .map_err(Error::Sync)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use async_std::task::block_on;
+
+    use super::*;
+    use crate::get_templates::get_templates_from;
+    use crate::test_dir::TestDir;
+    use crate::test_templates::templates;
+
+    #[test]
+    fn previous_file_is_kept_as_backup() {
+        let dir = TestDir::new("set-backup");
+        let fp_file = dir.file("templates");
+        let keys = StorageKeys::default();
+        block_on(async {
+            set_templates_to(dir.path(), &fp_file, &templates(&["old"]), &keys)
+                .await
+                .unwrap();
+            assert!(!Path::new(&get_backup_fp_file(&fp_file)).exists());
+            set_templates_to(dir.path(), &fp_file, &templates(&["new"]), &keys)
+                .await
+                .unwrap();
+            let backup = get_templates_from(&get_backup_fp_file(&fp_file), &keys)
+                .await
+                .unwrap();
+            assert!(backup.contains_key("old"));
+            let templates = get_templates_from(&fp_file, &keys).await.unwrap();
+            assert!(templates.contains_key("new"));
+            assert!(!Path::new(&get_temp_fp_file(&fp_file)).exists());
+        });
+    }
+
+    #[test]
+    fn shorter_file_replaces_longer_file() {
+        let dir = TestDir::new("set-shorter");
+        let fp_file = dir.file("templates");
+        let keys = StorageKeys::default();
+        block_on(async {
+            set_templates_to(dir.path(), &fp_file, &templates(&["1", "2", "3"]), &keys)
+                .await
+                .unwrap();
+            set_templates_to(dir.path(), &fp_file, &templates(&["1"]), &keys)
+                .await
+                .unwrap();
+            let templates = get_templates_from(&fp_file, &keys).await.unwrap();
+            assert_eq!(templates.len(), 1);
+        });
+    }
+}
@@ -0,0 +1,390 @@
+use std::future::Future;
+use std::ops::{Deref, DerefMut};
+use std::pin::pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, PoisonError, RwLock};
+use std::time::{Duration, Instant};
+
+use async_std::future::timeout;
+use async_std::sync::{Mutex, MutexGuard, MutexGuardArc};
+use log::warn;
+use rand::random;
+use rust_fp::fingerprint_driver::{
+    CancelSignal, Canceller, Capabilities, CaptureType, DeviceInfo, DriverError, EnrollStepResult,
+    FingerDownListener, Image, MatchOutput, OpenedFingerprintDriver, SelfTestReport, SensorId,
+};
+use zbus::export::futures_util::future::BoxFuture;
+
+use crate::caller::Caller;
+
+/// Takes the place of a sensor that was unplugged or couldn't be opened. Everything fails with [`DriverError::Unavailable`].
+struct UnavailableDriver {
+    cancel_signal: CancelSignal,
+}
+
+impl OpenedFingerprintDriver for UnavailableDriver {
+    fn canceller(&self) -> Canceller {
+        self.cancel_signal.canceller()
+    }
+
+    fn start_or_continue_enroll(&mut self, _user: u32) -> BoxFuture<'_, EnrollStepResult> {
+        Box::pin(async { Err(DriverError::Unavailable.into()) })
+    }
+
+    fn abort_enroll(&mut self) -> Result<(), DriverError> {
+        Ok(())
+    }
+
+    fn get_max_templates(&mut self) -> Result<usize, DriverError> {
+        Err(DriverError::Unavailable)
+    }
+
+    fn sensor_id(&self) -> SensorId {
+        Default::default()
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Default::default()
+    }
+
+    fn take_reset_detected(&mut self) -> bool {
+        false
+    }
+
+    fn match_templates<'a>(
+        &'a mut self,
+        _user: u32,
+        _templates: &'a [Vec<u8>],
+    ) -> BoxFuture<'a, Result<MatchOutput, DriverError>> {
+        Box::pin(async { Err(DriverError::Unavailable) })
+    }
+
+    fn wait_finger_down(&mut self) -> BoxFuture<'_, Result<(), DriverError>> {
+        Box::pin(async { Err(DriverError::Unavailable) })
+    }
+
+    fn wait_finger_up(&mut self) -> BoxFuture<'_, Result<(), DriverError>> {
+        Box::pin(async { Err(DriverError::Unavailable) })
+    }
+
+    fn capture_image(
+        &mut self,
+        _capture_type: CaptureType,
+    ) -> BoxFuture<'_, Result<Image, DriverError>> {
+        Box::pin(async { Err(DriverError::Unavailable) })
+    }
+
+    fn device_info(&mut self) -> Result<DeviceInfo, DriverError> {
+        Err(DriverError::Unavailable)
+    }
+
+    fn self_test(&mut self) -> BoxFuture<'_, Result<SelfTestReport, DriverError>> {
+        Box::pin(async { Err(DriverError::Unavailable) })
+    }
+}
+
+/// What is known about the opened driver without locking it
+struct Opened {
+    canceller: Canceller,
+    finger_down_listener: Option<FingerDownListener>,
+    sensor_id: SensorId,
+    capabilities: Capabilities,
+    available: bool,
+    legacy_seed: bool,
+}
+
+/// Enrolling takes multiple enroll steps, and the sensor keeps its enroll session between them.
+/// Nothing else can use the sensor until the session ends, no matter which interface started it.
+#[derive(Debug)]
+pub struct EnrollSession {
+    pub id: u32,
+    /// The client that started the session. Nobody else can continue or abort it.
+    pub owner: Caller,
+    /// The uid of the user that the template is enrolled for
+    pub user: u32,
+    /// If an enroll step is running. The session doesn't time out while waiting for a finger.
+    pub stepping: bool,
+    /// When the last enroll step ended
+    pub last_activity: Instant,
+}
+
+impl EnrollSession {
+    /// A session with a new random id, whose first enroll step is about to run
+    pub fn new(owner: Caller, user: u32) -> Self {
+        Self {
+            id: random(),
+            owner,
+            user,
+            stepping: true,
+            last_activity: Instant::now(),
+        }
+    }
+}
+
+/// An opened driver which can be used by multiple D-Bus interfaces.
+/// Only one of them can use the driver at a time, but any of them can cancel what it's doing.
+/// The driver can be replaced if the sensor is unplugged or plugged back in.
+#[derive(Clone)]
+pub struct SharedDriver {
+    driver: Arc<Mutex<Box<dyn OpenedFingerprintDriver>>>,
+    // Kept outside of the mutex so that an in-flight operation can be cancelled while it holds the driver
+    opened: Arc<RwLock<Opened>>,
+    name: &'static str,
+    sleeping: Arc<AtomicBool>,
+    /// The client that is using the driver, if it was locked with [`SharedDriver::lock_for`]
+    owner: Arc<RwLock<Option<Caller>>>,
+    enroll_session: Arc<Mutex<Option<EnrollSession>>>,
+}
+
+/// The driver, locked by a client. The client is the driver's owner until this is dropped.
+pub struct OwnedDriverGuard<'a> {
+    guard: MutexGuard<'a, Box<dyn OpenedFingerprintDriver>>,
+    owner: &'a RwLock<Option<Caller>>,
+}
+
+impl Deref for OwnedDriverGuard<'_> {
+    type Target = Box<dyn OpenedFingerprintDriver>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.guard
+    }
+}
+
+impl DerefMut for OwnedDriverGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.guard
+    }
+}
+
+impl Drop for OwnedDriverGuard<'_> {
+    fn drop(&mut self) {
+        *self.owner.write().unwrap_or_else(PoisonError::into_inner) = None;
+    }
+}
+
+impl SharedDriver {
+    /// `driver` is `None` if the sensor isn't available yet
+    pub fn new(name: &'static str, driver: Option<Box<dyn OpenedFingerprintDriver>>) -> Self {
+        let (driver, opened) = Self::with_opened(driver);
+        Self {
+            driver: Arc::new(Mutex::new(driver)),
+            opened: Arc::new(RwLock::new(opened)),
+            name,
+            sleeping: Default::default(),
+            owner: Default::default(),
+            enroll_session: Default::default(),
+        }
+    }
+
+    fn with_opened(
+        driver: Option<Box<dyn OpenedFingerprintDriver>>,
+    ) -> (Box<dyn OpenedFingerprintDriver>, Opened) {
+        let available = driver.is_some();
+        let driver = driver.unwrap_or_else(|| {
+            Box::new(UnavailableDriver {
+                cancel_signal: Default::default(),
+            })
+        });
+        let opened = Opened {
+            canceller: driver.canceller(),
+            finger_down_listener: driver.finger_down_listener(),
+            sensor_id: driver.sensor_id(),
+            capabilities: driver.capabilities(),
+            available,
+            legacy_seed: driver.uses_legacy_seed(),
+        };
+        (driver, opened)
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// The sensor id is empty while the sensor is unavailable
+    pub fn sensor_id(&self) -> SensorId {
+        self.opened
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .sensor_id
+            .clone()
+    }
+
+    /// The capabilities are empty while the sensor is unavailable
+    pub fn capabilities(&self) -> Capabilities {
+        self.opened
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .capabilities
+            .clone()
+    }
+
+    /// If templates enrolled now only work with the legacy seed
+    pub fn uses_legacy_seed(&self) -> bool {
+        self.opened
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .legacy_seed
+    }
+
+    /// Told when the sensor notices a finger during an enroll step or match. `None` if the driver can't tell.
+    pub fn finger_down_listener(&self) -> Option<FingerDownListener> {
+        self.opened
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .finger_down_listener
+            .clone()
+    }
+
+    /// If the sensor is plugged in and opened
+    pub fn is_available(&self) -> bool {
+        self.opened
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .available
+    }
+
+    /// Fails with [`DriverError::Unavailable`] if the sensor isn't available, so that clients get an error right away
+    pub fn check_available(&self) -> Result<(), DriverError> {
+        match self.is_available() {
+            true => Ok(()),
+            false => Err(DriverError::Unavailable),
+        }
+    }
+
+    /// Waits until nothing else is using the driver
+    pub async fn lock(&self) -> MutexGuard<'_, Box<dyn OpenedFingerprintDriver>> {
+        self.driver.lock().await
+    }
+
+    /// Waits until nothing else is using the driver, and remembers that `owner` is using it
+    pub async fn lock_for(&self, owner: &Caller) -> OwnedDriverGuard<'_> {
+        let guard = self.driver.lock().await;
+        *self.owner.write().unwrap_or_else(PoisonError::into_inner) = Some(owner.clone());
+        OwnedDriverGuard {
+            guard,
+            owner: &self.owner,
+        }
+    }
+
+    /// The client that is using the driver. `None` if nothing is using it, or if it wasn't locked for a client.
+    pub fn owner(&self) -> Option<Caller> {
+        self.owner
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// If something is using the driver
+    pub fn is_busy(&self) -> bool {
+        self.driver.try_lock().is_none()
+    }
+
+    pub fn cancel(&self) {
+        self.opened
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .canceller
+            .cancel();
+    }
+
+    /// Keeps cancelling whatever is using the driver until the future is done
+    pub async fn cancel_until<T>(&self, future: impl Future<Output = T>) -> T {
+        let mut future = pin!(future);
+        // The operation may not be waiting for a finger yet, in which case the cancel doesn't do anything.
+        // So keep cancelling until it ends.
+        loop {
+            self.cancel();
+            if let Ok(output) = timeout(Duration::from_millis(100), &mut future).await {
+                break output;
+            }
+        }
+    }
+
+    /// Cancels whatever is using the driver until it stops using it
+    pub(crate) async fn lock_cancelling(&self) -> MutexGuard<'_, Box<dyn OpenedFingerprintDriver>> {
+        self.cancel_until(self.driver.lock()).await
+    }
+
+    /// Like [`Self::lock_for`], but fails with [`DriverError::Busy`] during an enroll session,
+    /// so that the sensor's enroll session isn't ended by something else
+    pub async fn lock_unless_enrolling(
+        &self,
+        owner: &Caller,
+    ) -> Result<OwnedDriverGuard<'_>, DriverError> {
+        let guard = self.lock_for(owner).await;
+        match self.is_enrolling().await {
+            true => Err(DriverError::Busy),
+            false => Ok(guard),
+        }
+    }
+
+    /// The enroll session, which is shared by every interface that enrolls with this driver
+    pub async fn enroll_session(&self) -> MutexGuard<'_, Option<EnrollSession>> {
+        self.enroll_session.lock().await
+    }
+
+    /// If an enroll session is active, which other uses of the sensor shouldn't interrupt
+    pub async fn is_enrolling(&self) -> bool {
+        self.enroll_session.lock().await.is_some()
+    }
+
+    /// Starts an enroll session whose steps are done right after each other, unless one is already active
+    pub async fn start_enroll_session(&self, owner: Caller, user: u32) -> Result<u32, DriverError> {
+        let mut enroll_session = self.enroll_session.lock().await;
+        match *enroll_session {
+            Some(_) => Err(DriverError::Busy),
+            None => Ok(enroll_session.insert(EnrollSession::new(owner, user)).id),
+        }
+    }
+
+    /// Ends the enroll session with the id, cancelling its enroll step if it's waiting for a finger.
+    /// Returns `false` if the session already ended.
+    pub async fn end_enroll_session(&self, id: u32) -> bool {
+        // The session isn't locked while waiting for the driver, so that a step waiting for a finger doesn't block it
+        let mut driver = self.lock_cancelling().await;
+        let mut enroll_session = self.enroll_session.lock().await;
+        // A cancelled enroll step ends the session by itself
+        if enroll_session.take_if(|session| session.id == id).is_none() {
+            return false;
+        }
+        if let Err(e) = driver.abort_enroll() {
+            warn!("Error aborting enroll: {e:?}");
+        }
+        true
+    }
+
+    /// Replaces the driver with a newly opened one, or with nothing if the sensor was unplugged.
+    /// Whatever is using the old driver is cancelled.
+    pub async fn replace(&self, driver: Option<Box<dyn OpenedFingerprintDriver>>) {
+        let mut guard = self.lock_cancelling().await;
+        let (driver, opened) = Self::with_opened(driver);
+        *guard = driver;
+        *self.opened.write().unwrap_or_else(PoisonError::into_inner) = opened;
+    }
+
+    /// Cancels whatever is using the driver and gets the sensor ready for the computer sleeping.
+    /// Nothing else can use the driver until the guard is given to [`Self::resume`].
+    /// The guard doesn't borrow the driver, so that resuming can happen in its own task.
+    pub async fn suspend(&self) -> MutexGuardArc<Box<dyn OpenedFingerprintDriver>> {
+        self.sleeping.store(true, Ordering::Relaxed);
+        let mut guard = self.cancel_until(self.driver.lock_arc()).await;
+        if let Err(e) = guard.suspend() {
+            warn!("Error preparing {} for sleep: {e}", self.name);
+        }
+        guard
+    }
+
+    /// Gets the sensor ready after the computer woke up, and lets other things use the driver again
+    pub async fn resume(&self, mut guard: MutexGuardArc<Box<dyn OpenedFingerprintDriver>>) {
+        if let Err(e) = guard.resume().await {
+            warn!("Error getting {} ready after sleep: {e}", self.name);
+        }
+        self.sleeping.store(false, Ordering::Relaxed);
+    }
+
+    /// If the computer is sleeping. Operations that were cancelled because of this can be done again,
+    /// which waits until the sensor is ready after waking up.
+    pub fn is_sleeping(&self) -> bool {
+        self.sleeping.load(Ordering::Relaxed)
+    }
+}
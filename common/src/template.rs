@@ -1,3 +1,183 @@
 use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-pub type Templates = HashMap<String, Vec<u8>>;
+use rust_fp::fingerprint_driver::{SensorId, TemplateId};
+use serde::{Deserialize, Serialize};
+
+/// The start of every templates file, except for files from before the format was versioned
+pub const MAGIC: [u8; 8] = *b"RUST-FP\0";
+/// The version of the format after [`MAGIC`]. It is saved as 4 little endian bytes.
+pub const FORMAT_VERSION: u32 = 1;
+
+pub type Templates = HashMap<String, TemplateEntry>;
+
+/// What templates files used to have. Only used for reading old files.
+pub type LegacyTemplates = HashMap<String, Vec<u8>>;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TemplateEntry {
+    pub template: Vec<u8>,
+    pub metadata: TemplateMetadata,
+}
+
+/// Information about a template. Fields are `None` for templates that were saved before metadata was saved.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TemplateMetadata {
+    /// The name of the driver that enrolled the template
+    pub driver: Option<String>,
+    /// The sensor that enrolled the template
+    pub sensor: Option<SensorId>,
+    /// Unix time in seconds
+    pub created: Option<u64>,
+    /// Unix time in seconds
+    pub last_matched: Option<u64>,
+    pub match_count: u64,
+    /// How many times the sensor updated the template after matching it
+    pub update_count: u64,
+    /// The sensor encrypted the template with the legacy seed, so it only works with that seed
+    #[serde(default)]
+    pub legacy_seed: bool,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+impl TemplateEntry {
+    /// A template that was just enrolled
+    pub fn new(template: Vec<u8>, driver: &str, sensor: &SensorId, legacy_seed: bool) -> Self {
+        Self {
+            template,
+            metadata: TemplateMetadata {
+                driver: Some(driver.into()),
+                sensor: Some(sensor.clone()),
+                created: Some(now()),
+                legacy_seed,
+                ..Default::default()
+            },
+        }
+    }
+
+    /// A template from before metadata was saved, so nothing is known about it
+    pub fn legacy(template: Vec<u8>) -> Self {
+        Self {
+            template,
+            metadata: Default::default(),
+        }
+    }
+
+    /// If the template was saved by a version of rust-fp from before metadata was saved.
+    /// Those versions always used the legacy seed.
+    pub fn is_legacy(&self) -> bool {
+        self.metadata.driver.is_none()
+    }
+
+    /// If the template stops working when the sensor stops using the legacy seed
+    pub fn needs_legacy_seed(&self) -> bool {
+        self.is_legacy() || self.metadata.legacy_seed
+    }
+
+    pub fn id(&self) -> TemplateId {
+        TemplateId::new(&self.template)
+    }
+
+    /// Records that the template matched, and saves the updated template if there is one
+    pub fn record_match(&mut self, updated_template: Option<Vec<u8>>) {
+        self.metadata.last_matched = Some(now());
+        self.metadata.match_count += 1;
+        if let Some(updated_template) = updated_template {
+            self.template = updated_template;
+            self.metadata.update_count += 1;
+        }
+    }
+
+    /// If the template is known to be enrolled by a different driver or sensor, so it probably won't match
+    pub fn is_from_other_sensor(&self, driver: &str, sensor: &SensorId) -> bool {
+        self.metadata
+            .driver
+            .as_ref()
+            .is_some_and(|template_driver| template_driver != driver)
+            || self
+                .metadata
+                .sensor
+                .as_ref()
+                .is_some_and(|template_sensor| template_sensor.id != sensor.id)
+    }
+}
+
+/// Gives templates from before the format was versioned empty metadata
+pub fn from_legacy(templates: LegacyTemplates) -> Templates {
+    templates
+        .into_iter()
+        .map(|(label, template)| (label, TemplateEntry::legacy(template)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sensor(id: &str, hardware_version: &str) -> SensorId {
+        SensorId {
+            id: id.into(),
+            hardware_version: hardware_version.into(),
+            firmware_version: "RO 1, RW 2".into(),
+        }
+    }
+
+    #[test]
+    fn sensor_saved_without_firmware_version() {
+        let saved = rmp_serde::to_vec(&("a", "1")).unwrap();
+        let sensor = rmp_serde::from_slice::<SensorId>(&saved).unwrap();
+        assert_eq!(sensor.id, "a");
+        assert_eq!(sensor.firmware_version, "");
+    }
+
+    #[test]
+    fn same_sensor_with_other_hardware_version() {
+        let entry = TemplateEntry::new(vec![1, 2, 3], "cros_fp", &sensor("a", "1"), false);
+        assert!(!entry.is_from_other_sensor("cros_fp", &sensor("a", "2")));
+    }
+
+    #[test]
+    fn other_sensor_id() {
+        let entry = TemplateEntry::new(vec![1, 2, 3], "cros_fp", &sensor("a", "1"), false);
+        assert!(entry.is_from_other_sensor("cros_fp", &sensor("b", "1")));
+    }
+
+    #[test]
+    fn other_driver() {
+        let entry = TemplateEntry::new(vec![1, 2, 3], "cros_fp", &sensor("a", "1"), false);
+        assert!(entry.is_from_other_sensor("simulated", &sensor("a", "1")));
+    }
+
+    #[test]
+    fn only_templates_without_metadata_are_legacy() {
+        assert!(TemplateEntry::legacy(vec![1, 2, 3]).is_legacy());
+        assert!(
+            !TemplateEntry::new(vec![1, 2, 3], "cros_fp", &sensor("a", "1"), false).is_legacy()
+        );
+    }
+
+    #[test]
+    fn legacy_seed_templates() {
+        assert!(TemplateEntry::legacy(vec![1, 2, 3]).needs_legacy_seed());
+        assert!(
+            TemplateEntry::new(vec![1, 2, 3], "cros_fp", &sensor("a", "1"), true)
+                .needs_legacy_seed()
+        );
+        assert!(
+            !TemplateEntry::new(vec![1, 2, 3], "cros_fp", &sensor("a", "1"), false)
+                .needs_legacy_seed()
+        );
+    }
+
+    #[test]
+    fn legacy_templates_are_from_any_sensor() {
+        let entry = TemplateEntry::legacy(vec![1, 2, 3]);
+        assert!(!entry.is_from_other_sensor("cros_fp", &sensor("a", "1")));
+    }
+}
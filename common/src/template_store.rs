@@ -0,0 +1,406 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::fs::Permissions;
+use std::io::{self, ErrorKind};
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::sync::Arc;
+
+use async_std::fs::{create_dir_all, read_dir, remove_file, rename, set_permissions, File};
+use async_std::stream::StreamExt;
+use async_std::sync::Mutex;
+use log::{info, warn};
+
+use crate::encryption::{self, StorageKey, StorageKeys};
+use crate::fp_file::{
+    get_backup_fp_file, get_daemon_fp_dir, get_daemon_fp_file, get_legacy_storage_key_file,
+    get_new_storage_key_file,
+};
+use crate::get_templates::{self, get_templates_from};
+use crate::template::Templates;
+use crate::update_templates::{self, update_templates_in};
+
+#[derive(Debug)]
+pub enum Error {
+    Get(get_templates::Error),
+    Update(update_templates::Error),
+    CreateDir(io::Error),
+    Permissions(io::Error),
+    Key(encryption::Error),
+    ReadDir(io::Error),
+    ReplaceKey(io::Error),
+}
+
+impl std::error::Error for Error {}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Get(e) => {
+                write!(f, "Error getting templates: {}", e)
+            }
+            Self::Update(e) => {
+                write!(f, "Error updating templates: {}", e)
+            }
+            Self::CreateDir(e) => {
+                write!(f, "Error creating dir: {:#?}", e)
+            }
+            Self::Permissions(e) => {
+                write!(f, "Error setting permissions: {:#?}", e)
+            }
+            Self::Key(e) => {
+                write!(f, "Error with storage key: {}", e)
+            }
+            Self::ReadDir(e) => {
+                write!(f, "Error reading dir: {:#?}", e)
+            }
+            Self::ReplaceKey(e) => {
+                write!(f, "Error replacing storage key: {:#?}", e)
+            }
+        }
+    }
+}
+
+impl From<Error> for zbus::fdo::Error {
+    fn from(e: Error) -> Self {
+        Self::Failed(format!("{e}"))
+    }
+}
+
+struct State {
+    cache: HashMap<u32, Templates>,
+    keys: StorageKeys,
+}
+
+/// Every user's templates, stored by the daemon so that users can't read or replace templates.
+/// Templates are kept in memory after they are read. Clones share the same cache.
+#[derive(Clone)]
+pub struct TemplateStore {
+    templates_dir: Arc<str>,
+    /// Where the key is saved, usually from [`crate::fp_file::get_storage_key_file`]
+    key_file: Arc<str>,
+    /// If templates files should be encrypted
+    encrypt: bool,
+    state: Arc<Mutex<State>>,
+}
+
+impl TemplateStore {
+    /// `templates_dir` is usually from [`crate::fp_file::get_templates_dir`].
+    /// If `encrypt` is set, templates files are encrypted with a key that is saved in `key_file`.
+    /// A key that an older version saved in `templates_dir` is moved to `key_file`.
+    /// Templates that are already saved get encrypted or decrypted if needed, and an interrupted [`Self::rekey`] is finished.
+    pub async fn open(templates_dir: &str, key_file: &str, encrypt: bool) -> Result<Self, Error> {
+        let store = Self {
+            templates_dir: templates_dir.into(),
+            key_file: key_file.into(),
+            encrypt,
+            state: Arc::new(Mutex::new(State {
+                cache: Default::default(),
+                keys: Default::default(),
+            })),
+        };
+        let legacy_key_file = get_legacy_storage_key_file(templates_dir);
+        store.move_key(&legacy_key_file, key_file).await?;
+        store
+            .move_key(
+                &get_new_storage_key_file(&legacy_key_file),
+                &get_new_storage_key_file(key_file),
+            )
+            .await?;
+        let key = StorageKey::read(key_file).await.map_err(Error::Key)?;
+        let new_key_file = get_new_storage_key_file(key_file);
+        let new_key = match StorageKey::read(&new_key_file).await {
+            // The new key is saved before any templates are encrypted with it, so nothing needs it
+            Err(encryption::Error::KeyLength(_)) => {
+                warn!("Removing partially written {new_key_file}");
+                remove_file(&new_key_file)
+                    .await
+                    .map_err(Error::ReplaceKey)?;
+                None
+            }
+            result => result.map_err(Error::Key)?,
+        };
+        let mut state = store.state.lock().await;
+        state.keys.old.extend(key.clone());
+        match (new_key, key, encrypt) {
+            (Some(new_key), _, _) => {
+                info!("Finishing an interrupted rekey");
+                state.keys.current = Some(new_key);
+                store.finish_rekey(&mut state).await?;
+            }
+            (None, Some(key), true) => {
+                state.keys = StorageKeys {
+                    current: Some(key),
+                    old: Default::default(),
+                };
+            }
+            (None, None, true) => {
+                info!("Encrypting templates");
+                store
+                    .rekey_to(&mut state, Some(StorageKey::random()))
+                    .await?;
+            }
+            // Templates that are still encrypted can be read, and get saved unencrypted
+            (None, _, false) => {}
+        }
+        drop(state);
+        Ok(store)
+    }
+
+    async fn create_templates_dir(&self) -> Result<(), Error> {
+        create_dir_all(&*self.templates_dir)
+            .await
+            .map_err(Error::CreateDir)?;
+        set_permissions(&*self.templates_dir, Permissions::from_mode(0o700))
+            .await
+            .map_err(Error::Permissions)?;
+        Ok(())
+    }
+
+    /// Only root can read the key, but the directory it's in is left as it is because it usually has other files
+    async fn create_key_dir(&self) -> Result<(), Error> {
+        if let Some(key_dir) = Path::new(&*self.key_file).parent() {
+            create_dir_all(key_dir).await.map_err(Error::CreateDir)?;
+        }
+        Ok(())
+    }
+
+    /// Moves a key from where an older version saved it. A key that is already at `to` is never replaced.
+    async fn move_key(&self, from: &str, to: &str) -> Result<(), Error> {
+        let Some(key) = StorageKey::read(from).await.map_err(Error::Key)? else {
+            return Ok(());
+        };
+        match StorageKey::read(to).await.map_err(Error::Key)? {
+            Some(_) => {
+                warn!("{from} isn't used because {to} exists. Remove {from} if nothing is encrypted with it.");
+                Ok(())
+            }
+            None => {
+                info!("Moving {from} to {to}, so that it isn't saved with the templates");
+                self.create_key_dir().await?;
+                key.write(to).await.map_err(Error::Key)?;
+                remove_file(from).await.map_err(Error::ReplaceKey)
+            }
+        }
+    }
+
+    async fn load<'a>(&self, state: &'a mut State, uid: u32) -> Result<&'a mut Templates, Error> {
+        Ok(match state.cache.entry(uid) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(
+                get_templates_from(&get_daemon_fp_file(&self.templates_dir, uid), &state.keys)
+                    .await
+                    .map_err(Error::Get)?,
+            ),
+        })
+    }
+
+    pub async fn get_templates(&self, uid: u32) -> Result<Templates, Error> {
+        Ok(self.load(&mut *self.state.lock().await, uid).await?.clone())
+    }
+
+    /// Changes one user's templates. The templates can't be changed by anything else in the meantime.
+    /// The file is read again while it's locked, in case another process changed it.
+    pub async fn update_templates<T>(
+        &self,
+        uid: u32,
+        update: impl FnOnce(&mut Templates) -> T,
+    ) -> Result<T, Error> {
+        // Hold the lock for the whole read-modify-write
+        let mut state = self.state.lock().await;
+        self.create_templates_dir().await?;
+        let mut saved_templates = None;
+        let output = update_templates_in(
+            &get_daemon_fp_dir(&self.templates_dir, uid),
+            &get_daemon_fp_file(&self.templates_dir, uid),
+            &state.keys,
+            |templates| {
+                let output = update(templates);
+                saved_templates = Some(templates.clone());
+                output
+            },
+        )
+        .await
+        .map_err(Error::Update)?;
+        // Only update the cache after the templates were saved, so it's always the same as the file
+        if let Some(templates) = saved_templates {
+            state.cache.insert(uid, templates);
+        }
+        Ok(output)
+    }
+
+    /// The users that have a templates file
+    pub async fn uids(&self) -> Result<Vec<u32>, Error> {
+        let mut uids = Vec::new();
+        match read_dir(&*self.templates_dir).await {
+            Ok(mut entries) => {
+                while let Some(entry) = entries.next().await {
+                    let entry = entry.map_err(Error::ReadDir)?;
+                    let uid = entry.file_name().to_str().and_then(|uid| uid.parse().ok());
+                    if let Some(uid) = uid {
+                        if Path::new(&get_daemon_fp_file(&self.templates_dir, uid)).exists() {
+                            uids.push(uid);
+                        }
+                    }
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(Error::ReadDir(e)),
+        }
+        Ok(uids)
+    }
+
+    /// Changes every user's templates, one user at a time
+    pub async fn update_every_user(
+        &self,
+        mut update: impl FnMut(&mut Templates),
+    ) -> Result<(), Error> {
+        for uid in self.uids().await? {
+            self.update_templates(uid, &mut update).await?;
+        }
+        Ok(())
+    }
+
+    /// Saves every user's templates again with a new key, so that the old key is useless.
+    /// If encryption is turned off, every user's templates are saved unencrypted and the key is deleted.
+    pub async fn rekey(&self) -> Result<(), Error> {
+        let mut state = self.state.lock().await;
+        let new_key = self.encrypt.then(StorageKey::random);
+        self.rekey_to(&mut state, new_key).await
+    }
+
+    /// The new key is saved next to the current key until every file is saved again,
+    /// so that an interrupted rekey can be finished later
+    async fn rekey_to(&self, state: &mut State, new_key: Option<StorageKey>) -> Result<(), Error> {
+        if let Some(new_key) = &new_key {
+            self.create_key_dir().await?;
+            new_key
+                .write(&get_new_storage_key_file(&self.key_file))
+                .await
+                .map_err(Error::Key)?;
+        }
+        let old_key = state.keys.current.take();
+        state.keys.old.extend(old_key);
+        state.keys.current = new_key;
+        self.finish_rekey(state).await
+    }
+
+    /// Saves every user's templates with the current key, and then forgets the old keys
+    async fn finish_rekey(&self, state: &mut State) -> Result<(), Error> {
+        for uid in self.uids().await? {
+            update_templates_in(
+                &get_daemon_fp_dir(&self.templates_dir, uid),
+                &get_daemon_fp_file(&self.templates_dir, uid),
+                &state.keys,
+                |_templates| (),
+            )
+            .await
+            .map_err(Error::Update)?;
+            // The backup is still encrypted with the old key
+            let backup_fp_file = get_backup_fp_file(&get_daemon_fp_file(&self.templates_dir, uid));
+            match remove_file(&backup_fp_file).await {
+                Err(e) if e.kind() != ErrorKind::NotFound => Err(Error::ReplaceKey(e)),
+                _ => Ok(()),
+            }?;
+        }
+        let key_file = &*self.key_file;
+        match state.keys.current {
+            Some(_) => {
+                rename(get_new_storage_key_file(key_file), key_file)
+                    .await
+                    .map_err(Error::ReplaceKey)?;
+                // Make sure the rename is saved
+                let key_dir = Path::new(key_file).parent().unwrap_or(Path::new("/"));
+                File::open(key_dir)
+                    .await
+                    .map_err(Error::ReplaceKey)?
+                    .sync_all()
+                    .await
+                    .map_err(Error::ReplaceKey)?;
+            }
+            None => match remove_file(key_file).await {
+                Err(e) if e.kind() != ErrorKind::NotFound => Err(Error::ReplaceKey(e)),
+                _ => Ok(()),
+            }?,
+        }
+        state.keys.old.clear();
+        info!("Rekeyed templates");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::read;
+
+    use async_std::task::block_on;
+
+    use super::*;
+    use crate::template::TemplateEntry;
+    use crate::test_dir::TestDir;
+
+    #[test]
+    fn legacy_key_is_moved() {
+        let dir = TestDir::new("move-key");
+        let templates_dir = dir.file("templates");
+        let key_file = dir.file("etc/storage-key");
+        let legacy_key_file = get_legacy_storage_key_file(&templates_dir);
+        block_on(async {
+            create_dir_all(&templates_dir).await.unwrap();
+            StorageKey::random().write(&legacy_key_file).await.unwrap();
+            let key = read(&legacy_key_file).unwrap();
+            TemplateStore::open(&templates_dir, &key_file, true)
+                .await
+                .unwrap();
+            assert!(!Path::new(&legacy_key_file).exists());
+            assert_eq!(read(&key_file).unwrap(), key);
+        });
+    }
+
+    #[test]
+    fn legacy_key_does_not_replace_key() {
+        let dir = TestDir::new("keep-key");
+        let templates_dir = dir.file("templates");
+        let key_file = dir.file("storage-key");
+        let legacy_key_file = get_legacy_storage_key_file(&templates_dir);
+        block_on(async {
+            create_dir_all(&templates_dir).await.unwrap();
+            StorageKey::random().write(&legacy_key_file).await.unwrap();
+            StorageKey::random().write(&key_file).await.unwrap();
+            let key = read(&key_file).unwrap();
+            TemplateStore::open(&templates_dir, &key_file, true)
+                .await
+                .unwrap();
+            assert!(Path::new(&legacy_key_file).exists());
+            assert_eq!(read(&key_file).unwrap(), key);
+        });
+    }
+
+    #[test]
+    fn encrypted_templates_can_be_read_again() {
+        let dir = TestDir::new("store-encrypted");
+        let templates_dir = dir.file("templates");
+        let key_file = dir.file("storage-key");
+        block_on(async {
+            let store = TemplateStore::open(&templates_dir, &key_file, true)
+                .await
+                .unwrap();
+            store
+                .update_templates(1000, |templates| {
+                    templates.insert("finger".into(), TemplateEntry::legacy(vec![1, 2, 3]))
+                })
+                .await
+                .unwrap();
+            assert!(!Path::new(&get_legacy_storage_key_file(&templates_dir)).exists());
+            let store = TemplateStore::open(&templates_dir, &key_file, true)
+                .await
+                .unwrap();
+            assert!(store
+                .get_templates(1000)
+                .await
+                .unwrap()
+                .contains_key("finger"));
+        });
+    }
+}
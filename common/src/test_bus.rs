@@ -0,0 +1,109 @@
+use std::collections::HashMap;
+use std::fs::write;
+use std::io::{BufRead, BufReader, ErrorKind};
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex};
+
+use serde::Serialize;
+use zbus::interface;
+use zbus::zvariant::{OwnedValue, Type};
+
+use crate::test_dir::TestDir;
+
+/// A private bus, which is stopped when it's dropped
+pub struct Bus {
+    daemon: Child,
+    pub address: String,
+    _dir: TestDir,
+}
+
+impl Drop for Bus {
+    fn drop(&mut self) {
+        let _ = self.daemon.kill();
+        let _ = self.daemon.wait();
+    }
+}
+
+/// Starts a private bus with `dbus-daemon`, which needs to be installed
+pub fn start_bus(name: &str) -> Bus {
+    let dir = TestDir::new(name);
+    let config_file = dir.file("bus.conf");
+    write(
+        &config_file,
+        format!(
+            r#"<busconfig>
+  <type>session</type>
+  <listen>unix:dir={}</listen>
+  <auth>EXTERNAL</auth>
+  <policy context="default">
+    <allow send_destination="*" eavesdrop="true"/>
+    <allow eavesdrop="true"/>
+    <allow own="*"/>
+  </policy>
+</busconfig>"#,
+            dir.path()
+        ),
+    )
+    .unwrap();
+    let mut daemon = match Command::new("dbus-daemon")
+        .arg(format!("--config-file={config_file}"))
+        .args(["--nofork", "--print-address"])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+    {
+        Ok(daemon) => daemon,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            panic!("dbus-daemon isn't installed. It is needed to test the D-Bus interface.")
+        }
+        Err(e) => panic!("Error starting dbus-daemon: {e}"),
+    };
+    let mut address = String::new();
+    BufReader::new(daemon.stdout.take().unwrap())
+        .read_line(&mut address)
+        .unwrap();
+    Bus {
+        daemon,
+        address: address.trim().into(),
+        _dir: dir,
+    }
+}
+
+#[derive(Serialize, Type)]
+struct StubResult {
+    is_authorized: bool,
+    is_challenge: bool,
+    details: HashMap<String, String>,
+}
+
+/// Stands in for polkit, and remembers who it was asked about and for which action
+pub struct StubAuthority {
+    pub authorized: bool,
+    pub challenge: bool,
+    pub subjects: Arc<Mutex<Vec<(String, String)>>>,
+}
+
+#[interface(name = "org.freedesktop.PolicyKit1.Authority")]
+impl StubAuthority {
+    fn check_authorization(
+        &self,
+        subject: (String, HashMap<String, OwnedValue>),
+        action_id: String,
+        _details: HashMap<String, String>,
+        _flags: u32,
+        _cancellation_id: String,
+    ) -> StubResult {
+        let (kind, details) = subject;
+        let name = details
+            .get("name")
+            .and_then(|name| name.downcast_ref::<String>().ok())
+            .unwrap_or_default();
+        assert_eq!(kind, "system-bus-name");
+        self.subjects.lock().unwrap().push((name, action_id));
+        StubResult {
+            is_authorized: self.authorized,
+            is_challenge: self.challenge,
+            details: Default::default(),
+        }
+    }
+}
@@ -0,0 +1,33 @@
+use std::fs::{create_dir_all, remove_dir_all};
+use std::process;
+
+/// An empty directory for a test, which is removed when it's dropped
+pub struct TestDir(String);
+
+impl TestDir {
+    /// `name` must be different for each test, because tests run at the same time
+    pub fn new(name: &str) -> Self {
+        let dir = format!(
+            "{}/rust-fp-test-{}-{name}",
+            std::env::temp_dir().display(),
+            process::id()
+        );
+        let _ = remove_dir_all(&dir);
+        create_dir_all(&dir).unwrap();
+        Self(dir)
+    }
+
+    pub fn path(&self) -> &str {
+        &self.0
+    }
+
+    pub fn file(&self, name: &str) -> String {
+        format!("{}/{name}", self.0)
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = remove_dir_all(&self.0);
+    }
+}
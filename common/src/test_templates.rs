@@ -0,0 +1,25 @@
+use crate::encryption::{StorageKey, StorageKeys};
+use crate::template::{TemplateEntry, Templates};
+
+/// Templates with the labels, each with the same template
+pub fn templates(labels: &[&str]) -> Templates {
+    labels
+        .iter()
+        .map(|label| ((*label).to_owned(), TemplateEntry::legacy(vec![0; 100])))
+        .collect()
+}
+
+/// Keys with a new random current key, so that templates are encrypted
+pub fn random_keys() -> StorageKeys {
+    StorageKeys {
+        current: Some(StorageKey::random()),
+        old: Default::default(),
+    }
+}
+
+/// Changes the last byte of a file
+pub fn tamper(file: &str) {
+    let mut contents = std::fs::read(file).unwrap();
+    *contents.last_mut().unwrap() ^= 1;
+    std::fs::write(file, contents).unwrap();
+}
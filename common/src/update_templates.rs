@@ -0,0 +1,118 @@
+use std::fmt::{Display, Formatter};
+use std::io;
+
+use async_std::fs::create_dir_all;
+
+use crate::encryption::StorageKeys;
+use crate::get_templates::{self, get_templates_while_locked};
+use crate::lock_templates::{self, lock_templates, LockKind};
+use crate::set_templates::{self, set_templates_while_locked};
+use crate::template::Templates;
+
+#[derive(Debug)]
+pub enum Error {
+    CreateDir(io::Error),
+    Lock(lock_templates::Error),
+    Get(get_templates::Error),
+    Set(set_templates::Error),
+}
+
+impl std::error::Error for Error {}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::CreateDir(e) => {
+                write!(f, "Error creating dir: {:#?}", e)
+            }
+            Self::Lock(e) => {
+                write!(f, "Error locking file: {}", e)
+            }
+            Self::Get(e) => {
+                write!(f, "Error getting templates: {}", e)
+            }
+            Self::Set(e) => {
+                write!(f, "Error setting templates: {}", e)
+            }
+        }
+    }
+}
+
+/// Reads, changes, and writes a templates file while holding an exclusive lock,
+/// so that changes made by other processes at the same time aren't lost
+pub async fn update_templates_in<T>(
+    fp_dir: &str,
+    fp_file: &str,
+    keys: &StorageKeys,
+    update: impl FnOnce(&mut Templates) -> T,
+) -> Result<T, Error> {
+    create_dir_all(fp_dir).await.map_err(Error::CreateDir)?;
+    let _lock = lock_templates(fp_file, LockKind::Exclusive)
+        .await
+        .map_err(Error::Lock)?;
+    let mut templates = get_templates_while_locked(fp_file, keys)
+        .await
+        .map_err(Error::Get)?;
+    let output = update(&mut templates);
+    set_templates_while_locked(fp_dir, fp_file, &templates, keys)
+        .await
+        .map_err(Error::Set)?;
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{read, write};
+
+    use async_std::task::block_on;
+
+    use super::*;
+    use crate::get_templates::{get_templates_from, Error as GetError};
+    use crate::template::{LegacyTemplates, FORMAT_VERSION, MAGIC};
+    use crate::test_dir::TestDir;
+
+    #[test]
+    fn legacy_file_is_migrated() {
+        let dir = TestDir::new("migrate");
+        let fp_file = dir.file("templates");
+        let keys = StorageKeys::default();
+        let legacy_templates = LegacyTemplates::from([("finger".to_owned(), vec![1, 2, 3])]);
+        write(&fp_file, rmp_serde::to_vec(&legacy_templates).unwrap()).unwrap();
+        block_on(async {
+            let templates = get_templates_from(&fp_file, &keys).await.unwrap();
+            assert_eq!(templates["finger"].template, vec![1, 2, 3]);
+            assert!(templates["finger"].is_legacy());
+            update_templates_in(dir.path(), &fp_file, &keys, |_templates| ())
+                .await
+                .unwrap();
+            let contents = read(&fp_file).unwrap();
+            assert_eq!(
+                contents[..12],
+                [&MAGIC[..], &FORMAT_VERSION.to_le_bytes()].concat()
+            );
+            let templates = get_templates_from(&fp_file, &keys).await.unwrap();
+            assert_eq!(templates["finger"].template, vec![1, 2, 3]);
+        });
+    }
+
+    #[test]
+    fn newer_version_is_not_replaced() {
+        let dir = TestDir::new("newer-version");
+        let fp_file = dir.file("templates");
+        let keys = StorageKeys::default();
+        let contents = [&MAGIC[..], &(FORMAT_VERSION + 1).to_le_bytes(), &[0x80]].concat();
+        write(&fp_file, &contents).unwrap();
+        block_on(async {
+            assert!(matches!(
+                get_templates_from(&fp_file, &keys).await,
+                Err(GetError::UnsupportedVersion(version)) if version == FORMAT_VERSION + 1
+            ));
+            assert!(
+                update_templates_in(dir.path(), &fp_file, &keys, |templates| templates.clear())
+                    .await
+                    .is_err()
+            );
+        });
+        assert_eq!(read(&fp_file).unwrap(), contents);
+    }
+}
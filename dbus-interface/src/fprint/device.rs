@@ -0,0 +1,581 @@
+use std::future::Future;
+use std::sync::Arc;
+
+use async_std::sync::Mutex;
+use async_std::task::{spawn, JoinHandle};
+use log::{info, warn};
+use nix::unistd::User;
+use rust_fp::fingerprint_driver::{
+    DriverError, EnrollStepError, EnrollStepOutput, MatchOutput, NoMatchError, ScanType,
+};
+use rust_fp_common::caller::{
+    get_caller, get_caller_uid, get_target_user, wait_until_disconnected, Caller,
+};
+use rust_fp_common::polkit::{check_authorization, Action};
+use rust_fp_common::shared_driver::SharedDriver;
+use rust_fp_common::template::{TemplateEntry, Templates};
+use rust_fp_common::template_store::TemplateStore;
+use zbus::fdo;
+use zbus::message::Header;
+use zbus::names::OwnedUniqueName;
+use zbus::{interface, Connection, SignalContext};
+
+use crate::fprint::error::{self, FprintError};
+
+/// The finger names that `fprintd` clients use. They are used as the labels of the templates.
+const FINGER_NAMES: [&str; 10] = [
+    "left-thumb",
+    "left-index-finger",
+    "left-middle-finger",
+    "left-ring-finger",
+    "left-little-finger",
+    "right-thumb",
+    "right-index-finger",
+    "right-middle-finger",
+    "right-ring-finger",
+    "right-little-finger",
+];
+/// Verifying with this finger name matches any enrolled finger
+const ANY_FINGER: &str = "any";
+
+struct Claim {
+    /// The client that claimed the device
+    owner: Caller,
+    user: User,
+    /// The enroll or verify that is running or finished but not stopped yet
+    action: Option<JoinHandle<()>>,
+    /// Releases the claim if the client disconnects without releasing it, like `fprintd` does
+    watcher: JoinHandle<()>,
+}
+
+/// Cancels the action and waits for it to end
+async fn stop_action(driver: &SharedDriver, action: JoinHandle<()>) {
+    driver.cancel_until(action).await
+}
+
+/// Releases the claim once its client disconnects, so that a crashed client doesn't keep the device claimed
+async fn watch_claim(
+    claim: Arc<Mutex<Option<Claim>>>,
+    driver: SharedDriver,
+    connection: Connection,
+    sender: OwnedUniqueName,
+) {
+    if let Err(e) = wait_until_disconnected(&connection, &sender).await {
+        warn!("Error watching claim owner {sender}: {e:?}");
+        return;
+    }
+    let mut claim = claim.lock().await;
+    if let Some(Claim { action, .. }) = claim.take_if(|claim| claim.owner.name == sender) {
+        info!("{sender} disconnected without releasing the device");
+        if let Some(action) = action {
+            stop_action(&driver, action).await;
+        }
+    }
+}
+
+/// A `net.reactivated.Fprint.Device`, so that anything that works with `fprintd` works with `rust-fp`
+pub struct Device {
+    driver: SharedDriver,
+    store: TemplateStore,
+    claim: Arc<Mutex<Option<Claim>>>,
+}
+
+impl Device {
+    pub fn new(driver: SharedDriver, store: TemplateStore) -> Self {
+        Self {
+            driver,
+            store,
+            claim: Default::default(),
+        }
+    }
+
+    /// Gets the user that the caller is asking for. Only root can ask for other users.
+    async fn get_user(
+        connection: &Connection,
+        header: &Header<'_>,
+        username: &str,
+    ) -> error::Result<User> {
+        get_target_user(connection, header, username)
+            .await
+            .map_err(fprint_error)
+    }
+
+    async fn check_authorization(
+        connection: &Connection,
+        header: &Header<'_>,
+        action: Action,
+    ) -> error::Result<()> {
+        check_authorization(connection, header, action)
+            .await
+            .map_err(fprint_error)
+    }
+
+    fn get_claim<'a>(
+        claim: &'a mut Option<Claim>,
+        header: &Header<'_>,
+    ) -> error::Result<&'a mut Claim> {
+        match claim {
+            Some(claim) if header.sender() == Some(&claim.owner.name) => Ok(claim),
+            _ => Err(FprintError::ClaimDevice("Device was not claimed".into())),
+        }
+    }
+
+    /// Starts the action for the client that claimed the device and the claimed user, unless one is already running
+    async fn start_action<F: Future<Output = error::Result<JoinHandle<()>>>>(
+        &self,
+        header: &Header<'_>,
+        action: impl FnOnce(Caller, User) -> F,
+    ) -> error::Result<()> {
+        let mut claim = self.claim.lock().await;
+        let claim = Self::get_claim(&mut claim, header)?;
+        match claim.action {
+            Some(_) => Err(FprintError::AlreadyInUse(
+                "An enroll or verify is already in progress".into(),
+            )),
+            None => {
+                claim.action = Some(action(claim.owner.clone(), claim.user.clone()).await?);
+                Ok(())
+            }
+        }
+    }
+
+    async fn stop(&self, header: &Header<'_>) -> error::Result<()> {
+        let mut claim = self.claim.lock().await;
+        let action = Self::get_claim(&mut claim, header)?
+            .action
+            .take()
+            .ok_or_else(|| FprintError::NoActionInProgress("Nothing to stop".into()))?;
+        stop_action(&self.driver, action).await;
+        Ok(())
+    }
+}
+
+fn fprint_error(e: fdo::Error) -> FprintError {
+    match e {
+        fdo::Error::AccessDenied(message) => FprintError::PermissionDenied(message),
+        e => FprintError::Internal(format!("{e}")),
+    }
+}
+
+/// All devices share the user's templates, but each device only lists, verifies, deletes and replaces the ones its sensor enrolled
+fn is_own_template(driver: &SharedDriver, entry: &TemplateEntry) -> bool {
+    !entry.is_from_other_sensor(driver.name(), &driver.sensor_id())
+}
+
+async fn load_templates(store: &TemplateStore, user: &User) -> error::Result<Templates> {
+    store
+        .get_templates(user.uid.as_raw())
+        .await
+        .map_err(|e| FprintError::Internal(format!("{e}")))
+}
+
+/// Changes the user's templates in the same store that `org.rust_fp.RustFp2` uses
+async fn update_templates<T>(
+    store: &TemplateStore,
+    user: &User,
+    update: impl FnOnce(&mut Templates) -> T,
+) -> error::Result<T> {
+    store
+        .update_templates(user.uid.as_raw(), update)
+        .await
+        .map_err(|e| FprintError::Internal(format!("{e}")))
+}
+
+/// Does enroll steps in the enroll session with the id until enrolling is done, and then ends the session
+async fn enroll(
+    driver: SharedDriver,
+    store: TemplateStore,
+    ctxt: SignalContext<'static>,
+    owner: Caller,
+    user: User,
+    finger: String,
+    id: u32,
+) {
+    loop {
+        let step = driver
+            .lock_for(&owner)
+            .await
+            .start_or_continue_enroll(user.uid.as_raw())
+            .await;
+        info!("fprintd enroll step for {}: {step:?}", user.name);
+        let (result, done) = match step {
+            Ok(EnrollStepOutput::InProgress(_)) => ("enroll-stage-passed", false),
+            Ok(EnrollStepOutput::Complete(template)) => {
+                let template = TemplateEntry::new(
+                    template,
+                    driver.name(),
+                    &driver.sensor_id(),
+                    driver.uses_legacy_seed(),
+                );
+                let saved = update_templates(&store, &user, |templates| {
+                    match templates.get(&finger) {
+                        // It could have been enrolled on another device while enrolling
+                        Some(entry) if !is_own_template(&driver, entry) => false,
+                        _ => {
+                            templates.insert(finger.clone(), template);
+                            true
+                        }
+                    }
+                })
+                .await;
+                match saved {
+                    Ok(true) => ("enroll-completed", true),
+                    Ok(false) => {
+                        warn!("Not replacing {finger}, which was enrolled on another device");
+                        ("enroll-failed", true)
+                    }
+                    Err(e) => {
+                        warn!("Error saving enrolled template: {e:?}");
+                        ("enroll-failed", true)
+                    }
+                }
+            }
+            Err(EnrollStepError::LowQuality) => ("enroll-retry-scan", false),
+            Err(EnrollStepError::Immobile) => ("enroll-remove-and-retry", false),
+            Err(EnrollStepError::GenericError | EnrollStepError::Driver(_)) => {
+                ("enroll-failed", true)
+            }
+            // Whoever cancelled doesn't want any more signals
+            Err(EnrollStepError::Cancelled) => break,
+        };
+        if let Err(e) = Device::enroll_status(&ctxt, result, done).await {
+            warn!("Error emitting EnrollStatus: {e:?}");
+        }
+        if done {
+            break;
+        }
+    }
+    driver.end_enroll_session(id).await;
+}
+
+async fn verify(
+    driver: SharedDriver,
+    store: TemplateStore,
+    ctxt: SignalContext<'static>,
+    owner: Caller,
+    user: User,
+    labels: Vec<String>,
+    templates: Vec<Vec<u8>>,
+) {
+    loop {
+        // Matching would end an enroll session that started after verifying started
+        let output = match driver.lock_unless_enrolling(&owner).await {
+            Ok(mut driver) => driver.match_templates(user.uid.as_raw(), &templates).await,
+            Err(e) => Err(e),
+        };
+        info!("fprintd verify for {}: {output:?}", user.name);
+        let (result, done) = match output {
+            Ok(MatchOutput::Match(matched)) => {
+                let saved = update_templates(&store, &user, |templates| {
+                    if let Some(entry) = templates.get_mut(&labels[matched.index]) {
+                        entry.record_match(matched.updated_template);
+                    }
+                })
+                .await;
+                if let Err(e) = saved {
+                    warn!("Error saving matched template: {e:?}");
+                }
+                ("verify-match", true)
+            }
+            Ok(MatchOutput::NoMatch(None)) => ("verify-no-match", true),
+            Ok(MatchOutput::NoMatch(Some(NoMatchError::LowQuality))) => {
+                ("verify-retry-scan", false)
+            }
+            Ok(MatchOutput::NoMatch(Some(NoMatchError::Other))) => ("verify-unknown-error", true),
+            // Matching again waits until the sensor is ready after waking up
+            Err(DriverError::Cancelled) if driver.is_sleeping() => continue,
+            // Whoever cancelled doesn't want any more signals
+            Err(DriverError::Cancelled) => break,
+            Err(_) => ("verify-unknown-error", true),
+        };
+        if let Err(e) = Device::verify_status(&ctxt, result, done).await {
+            warn!("Error emitting VerifyStatus: {e:?}");
+        }
+        if done {
+            break;
+        }
+    }
+}
+
+#[interface(name = "net.reactivated.Fprint.Device")]
+impl Device {
+    async fn claim(
+        &self,
+        username: &str,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(connection)] connection: &Connection,
+    ) -> error::Result<()> {
+        let user = Self::get_user(connection, &header, username).await?;
+        let owner = get_caller(connection, &header)
+            .await
+            .map_err(fprint_error)?;
+        if !self.driver.is_available() {
+            return Err(FprintError::NoSuchDevice(format!(
+                "{}",
+                DriverError::Unavailable
+            )));
+        }
+        let mut claim = self.claim.lock().await;
+        match *claim {
+            Some(_) => Err(FprintError::AlreadyInUse(
+                "Device was already claimed".into(),
+            )),
+            None => {
+                let watcher = spawn(watch_claim(
+                    self.claim.clone(),
+                    self.driver.clone(),
+                    connection.clone(),
+                    owner.name.clone(),
+                ));
+                *claim = Some(Claim {
+                    owner,
+                    user,
+                    action: None,
+                    watcher,
+                });
+                Ok(())
+            }
+        }
+    }
+
+    async fn release(&self, #[zbus(header)] header: Header<'_>) -> error::Result<()> {
+        let mut claim = self.claim.lock().await;
+        Self::get_claim(&mut claim, &header)?;
+        if let Some(Claim {
+            action, watcher, ..
+        }) = claim.take()
+        {
+            // The watcher could be waiting for the claim, so don't wait for it to be cancelled
+            spawn(watcher.cancel());
+            if let Some(action) = action {
+                stop_action(&self.driver, action).await;
+            }
+        }
+        Ok(())
+    }
+
+    async fn list_enrolled_fingers(
+        &self,
+        username: &str,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(connection)] connection: &Connection,
+    ) -> error::Result<Vec<String>> {
+        let user = Self::get_user(connection, &header, username).await?;
+        let fingers = load_templates(&self.store, &user)
+            .await?
+            .into_iter()
+            .filter(|(_label, entry)| is_own_template(&self.driver, entry))
+            .map(|(label, _entry)| label)
+            .collect::<Vec<_>>();
+        match fingers.is_empty() {
+            true => Err(FprintError::NoEnrolledPrints(
+                "No fingers are enrolled".into(),
+            )),
+            false => Ok(fingers),
+        }
+    }
+
+    async fn delete_enrolled_fingers2(
+        &self,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(connection)] connection: &Connection,
+    ) -> error::Result<()> {
+        Self::check_authorization(connection, &header, Action::Delete).await?;
+        let mut claim = self.claim.lock().await;
+        let claim = Self::get_claim(&mut claim, &header)?;
+        update_templates(&self.store, &claim.user, |templates| {
+            templates.retain(|_label, entry| !is_own_template(&self.driver, entry))
+        })
+        .await
+    }
+
+    async fn delete_enrolled_finger(
+        &self,
+        finger_name: &str,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(connection)] connection: &Connection,
+    ) -> error::Result<()> {
+        Self::check_authorization(connection, &header, Action::Delete).await?;
+        let mut claim = self.claim.lock().await;
+        let claim = Self::get_claim(&mut claim, &header)?;
+        let removed = update_templates(&self.store, &claim.user, |templates| {
+            match templates.get(finger_name) {
+                Some(entry) if is_own_template(&self.driver, entry) => {
+                    templates.remove(finger_name)
+                }
+                _ => None,
+            }
+        })
+        .await?;
+        match removed {
+            Some(_template) => Ok(()),
+            None => Err(FprintError::NoEnrolledPrints(format!(
+                "{finger_name} is not enrolled"
+            ))),
+        }
+    }
+
+    async fn enroll_start(
+        &self,
+        finger_name: &str,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(signal_context)] ctxt: SignalContext<'_>,
+    ) -> error::Result<()> {
+        if !FINGER_NAMES.contains(&finger_name) {
+            return Err(FprintError::InvalidFingername(format!(
+                "Invalid finger name: {finger_name}"
+            )));
+        }
+        let user = {
+            let mut claim = self.claim.lock().await;
+            Self::get_claim(&mut claim, &header)?.user.clone()
+        };
+        let caller_uid = get_caller_uid(connection, &header)
+            .await
+            .map_err(fprint_error)?;
+        let action = match user.uid == caller_uid {
+            true => Action::EnrollOwn,
+            false => Action::EnrollAny,
+        };
+        Self::check_authorization(connection, &header, action).await?;
+        if load_templates(&self.store, &user)
+            .await?
+            .get(finger_name)
+            .is_some_and(|entry| !is_own_template(&self.driver, entry))
+        {
+            return Err(FprintError::AlreadyInUse(format!(
+                "{finger_name} is enrolled on another device. Delete it there first."
+            )));
+        }
+        self.start_action(&header, |owner, user| async {
+            let id = self
+                .driver
+                .start_enroll_session(owner.clone(), user.uid.as_raw())
+                .await
+                .map_err(|e| FprintError::AlreadyInUse(format!("{e}")))?;
+            Ok(spawn(enroll(
+                self.driver.clone(),
+                self.store.clone(),
+                ctxt.to_owned(),
+                owner,
+                user,
+                finger_name.to_owned(),
+                id,
+            )))
+        })
+        .await
+    }
+
+    async fn enroll_stop(&self, #[zbus(header)] header: Header<'_>) -> error::Result<()> {
+        self.stop(&header).await
+    }
+
+    async fn verify_start(
+        &self,
+        finger_name: &str,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(connection)] connection: &Connection,
+        #[zbus(signal_context)] ctxt: SignalContext<'_>,
+    ) -> error::Result<()> {
+        Self::check_authorization(connection, &header, Action::Verify).await?;
+        let user = {
+            let mut claim = self.claim.lock().await;
+            Self::get_claim(&mut claim, &header)?.user.clone()
+        };
+        let templates = load_templates(&self.store, &user)
+            .await?
+            .into_iter()
+            // Templates enrolled on other sensors can't match
+            .filter(|(_label, entry)| is_own_template(&self.driver, entry));
+        let (labels, templates): (Vec<_>, Vec<_>) = match finger_name {
+            "" | ANY_FINGER => templates
+                .map(|(label, entry)| (label, entry.template))
+                .unzip(),
+            finger_name => templates
+                .filter(|(label, _entry)| label == finger_name)
+                .map(|(label, entry)| (label, entry.template))
+                .unzip(),
+        };
+        if templates.is_empty() {
+            return Err(FprintError::NoEnrolledPrints(
+                "No matching fingers are enrolled".into(),
+            ));
+        }
+        if self.driver.is_enrolling().await {
+            return Err(FprintError::AlreadyInUse(format!("{}", DriverError::Busy)));
+        }
+        self.start_action(&header, |owner, user| async {
+            Ok(spawn(verify(
+                self.driver.clone(),
+                self.store.clone(),
+                ctxt.to_owned(),
+                owner,
+                user,
+                labels,
+                templates,
+            )))
+        })
+        .await?;
+        Self::verify_finger_selected(
+            &ctxt,
+            match finger_name {
+                "" => ANY_FINGER,
+                finger_name => finger_name,
+            },
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn verify_stop(&self, #[zbus(header)] header: Header<'_>) -> error::Result<()> {
+        self.stop(&header).await
+    }
+
+    #[zbus(signal)]
+    async fn verify_finger_selected(
+        ctxt: &SignalContext<'_>,
+        finger_name: &str,
+    ) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    async fn verify_status(ctxt: &SignalContext<'_>, result: &str, done: bool) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    async fn enroll_status(ctxt: &SignalContext<'_>, result: &str, done: bool) -> zbus::Result<()>;
+
+    #[zbus(property(emits_changed_signal = "const"), name = "name")]
+    async fn name(&self) -> String {
+        self.driver.name().into()
+    }
+
+    #[zbus(property(emits_changed_signal = "const"), name = "num-enroll-stages")]
+    async fn num_enroll_stages(&self) -> i32 {
+        self.driver.capabilities().enroll_stages as i32
+    }
+
+    #[zbus(property(emits_changed_signal = "const"), name = "scan-type")]
+    async fn scan_type(&self) -> String {
+        match self.driver.capabilities().scan_type {
+            ScanType::Press => "press",
+            ScanType::Swipe => "swipe",
+        }
+        .into()
+    }
+
+    #[zbus(property(emits_changed_signal = "false"), name = "finger-present")]
+    async fn finger_present(&self) -> bool {
+        false
+    }
+
+    #[zbus(property(emits_changed_signal = "false"), name = "finger-needed")]
+    async fn finger_needed(&self) -> bool {
+        matches!(
+            *self.claim.lock().await,
+            Some(Claim {
+                action: Some(_),
+                ..
+            })
+        )
+    }
+}
@@ -0,0 +1,19 @@
+use zbus::DBusError;
+
+/// The errors that `fprintd` clients know how to handle
+#[derive(Debug, DBusError)]
+#[zbus(prefix = "net.reactivated.Fprint.Error")]
+pub enum FprintError {
+    #[zbus(error)]
+    ZBus(zbus::Error),
+    NoSuchDevice(String),
+    ClaimDevice(String),
+    AlreadyInUse(String),
+    Internal(String),
+    PermissionDenied(String),
+    NoEnrolledPrints(String),
+    NoActionInProgress(String),
+    InvalidFingername(String),
+}
+
+pub type Result<T> = std::result::Result<T, FprintError>;
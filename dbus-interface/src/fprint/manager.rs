@@ -0,0 +1,32 @@
+use rust_fp_common::shared_driver::SharedDriver;
+use zbus::interface;
+use zbus::zvariant::OwnedObjectPath;
+
+use crate::fprint::error::{self, FprintError};
+
+pub struct Manager {
+    pub devices: Vec<(OwnedObjectPath, SharedDriver)>,
+    pub default_device: usize,
+}
+
+/// Only devices with a sensor that is plugged in are listed, like in `fprintd`
+#[interface(name = "net.reactivated.Fprint.Manager")]
+impl Manager {
+    async fn get_devices(&self) -> Vec<OwnedObjectPath> {
+        self.devices
+            .iter()
+            .filter(|(_path, driver)| driver.is_available())
+            .map(|(path, _driver)| path.clone())
+            .collect()
+    }
+
+    async fn get_default_device(&self) -> error::Result<OwnedObjectPath> {
+        self.devices
+            .get(self.default_device)
+            .into_iter()
+            .chain(&self.devices)
+            .find(|(_path, driver)| driver.is_available())
+            .map(|(path, _driver)| path.clone())
+            .ok_or_else(|| FprintError::NoSuchDevice("No devices available".into()))
+    }
+}
@@ -0,0 +1,5 @@
+//! An implementation of the `fprintd` D-Bus API, so that GNOME, GDM, `pam_fprintd` and `fprintd-*` work with `rust-fp`
+
+pub mod device;
+pub mod error;
+pub mod manager;
@@ -0,0 +1,122 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use async_std::channel::{unbounded, Sender};
+use async_std::task::sleep;
+use log::{info, warn};
+use nix::errno::Errno;
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify};
+use rust_fp::fingerprint_driver::FingerprintDriver;
+use rust_fp::key_provider::KeyProvider;
+use rust_fp_common::shared_driver::SharedDriver;
+use zbus::export::futures_util::future::pending;
+
+/// Device files are often deleted and created again right away, like when a kernel module is reloaded.
+/// Waiting for that to finish avoids opening a sensor that is about to disappear again.
+const SETTLE_TIME: Duration = Duration::from_millis(500);
+
+/// Sends the path of every file that is created or deleted in the directories
+fn start_watching(dirs: HashSet<&Path>, sender: Sender<PathBuf>) -> nix::Result<()> {
+    let inotify = Inotify::init(InitFlags::IN_CLOEXEC)?;
+    let mut watches = HashMap::new();
+    for dir in dirs {
+        let watch = inotify.add_watch(
+            dir,
+            AddWatchFlags::IN_CREATE
+                | AddWatchFlags::IN_DELETE
+                | AddWatchFlags::IN_MOVED_TO
+                | AddWatchFlags::IN_MOVED_FROM,
+        )?;
+        watches.insert(watch, dir.to_owned());
+    }
+    // Reading events blocks, so it gets its own thread
+    thread::spawn(move || loop {
+        match inotify.read_events() {
+            Ok(events) => {
+                for event in events {
+                    if let (Some(dir), Some(name)) = (watches.get(&event.wd), event.name) {
+                        if sender.send_blocking(dir.join(name)).is_err() {
+                            return;
+                        }
+                    }
+                }
+            }
+            Err(Errno::EINTR) => {}
+            Err(e) => {
+                warn!("Error reading device file changes: {e}");
+                return;
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Closes the driver, and opens it again if its sensor is there
+async fn reopen(
+    driver: &FingerprintDriver,
+    shared_driver: &SharedDriver,
+    key_provider: &Arc<dyn KeyProvider>,
+) {
+    // The old driver is using a device file that was deleted, so it can't be used even if the sensor came back
+    shared_driver.replace(None).await;
+    let compatible = match (driver.is_compatible)().await {
+        Ok(compatible) => compatible,
+        Err(e) => {
+            warn!("Error checking if {} is compatible: {e}", driver.name);
+            false
+        }
+    };
+    if !compatible {
+        info!("{} is unavailable", driver.name);
+        return;
+    }
+    info!("Opening {}.", driver.name);
+    match (driver.open_and_init)(key_provider.clone()).await {
+        Ok(opened_driver) => {
+            shared_driver.replace(Some(opened_driver)).await;
+            info!("Opened {}.", driver.name);
+        }
+        Err(e) => {
+            warn!("Error opening {}: {e}", driver.name);
+        }
+    }
+}
+
+/// Reopens drivers when their device files are created or deleted, so that sensors can be unplugged and plugged back in.
+/// `shared_drivers` has the shared driver of every driver in `drivers`. Never returns.
+pub async fn watch_devices(
+    drivers: &[FingerprintDriver],
+    shared_drivers: &[SharedDriver],
+    key_provider: Arc<dyn KeyProvider>,
+) {
+    let (sender, receiver) = unbounded();
+    let dirs = drivers
+        .iter()
+        .flat_map(|driver| driver.device_nodes)
+        .filter_map(|device_node| Path::new(device_node).parent())
+        .collect::<HashSet<_>>();
+    if let Err(e) = start_watching(dirs, sender) {
+        warn!("Not watching for sensors being plugged in or unplugged: {e}");
+    }
+    while let Ok(path) = receiver.recv().await {
+        let mut changed = HashSet::from([path]);
+        sleep(SETTLE_TIME).await;
+        while let Ok(path) = receiver.try_recv() {
+            changed.insert(path);
+        }
+        for (driver, shared_driver) in drivers.iter().zip(shared_drivers) {
+            if driver
+                .device_nodes
+                .iter()
+                .any(|device_node| changed.contains(Path::new(device_node)))
+            {
+                info!("Device files of {} changed", driver.name);
+                reopen(driver, shared_driver, &key_provider).await;
+            }
+        }
+    }
+    pending().await
+}
@@ -0,0 +1,24 @@
+use log::warn;
+use rust_fp_common::template::TemplateEntry;
+use rust_fp_common::template_store::TemplateStore;
+
+/// Checks the metadata of every user's saved templates for templates that only work with the legacy seed.
+/// If there are, the legacy seed needs to be used so that they keep working until they are enrolled again.
+/// Empty templates files, like the ones left after deleting every finger, don't count.
+pub async fn has_legacy_seed_templates(store: &TemplateStore) -> bool {
+    match store.uids().await {
+        Ok(uids) => {
+            for uid in uids {
+                match store.get_templates(uid).await {
+                    Ok(templates) if templates.values().any(TemplateEntry::needs_legacy_seed) => {
+                        return true
+                    }
+                    Ok(_) => {}
+                    Err(e) => warn!("Error checking templates of {uid} for legacy templates: {e}"),
+                }
+            }
+        }
+        Err(e) => warn!("Error checking for legacy templates: {e}"),
+    }
+    false
+}
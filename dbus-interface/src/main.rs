@@ -1,15 +1,38 @@
 #![warn(unused_crate_dependencies)]
 
-use log::info;
+use crate::fprint::device::Device;
+use crate::fprint::manager::Manager;
+use crate::hotplug::watch_devices;
+use crate::legacy::has_legacy_seed_templates;
+use crate::sleep::watch_sleep;
+use log::{info, warn};
 use rust_fp::drivers::get_drivers;
+use rust_fp::key_provider::{FileKeyProvider, KeyProvider};
+use rust_fp_common::config::get_config;
+use rust_fp_common::fp_file::{get_seed_file, get_storage_key_file, get_templates_dir};
+use rust_fp_common::manager_dbus::{self, get_device_path, MANAGER_PATH};
+use rust_fp_common::rust_fp2_dbus::RustFp2;
 use rust_fp_common::rust_fp_dbus::RustFp;
+use rust_fp_common::shared_driver::SharedDriver;
+use rust_fp_common::template_store::TemplateStore;
 use simple_logger::SimpleLogger;
 use std::collections::HashMap;
 use std::error::Error;
 use std::fmt::{Display, Formatter};
-use std::{io, thread};
+use std::io;
+use std::sync::Arc;
 use zbus::connection::Builder;
-use zbus::export::futures_util::future::join_all;
+use zbus::export::futures_util::future::{join, join_all};
+use zbus::zvariant::OwnedObjectPath;
+
+mod fprint;
+mod hotplug;
+mod legacy;
+mod sleep;
+
+fn get_fprint_device_path(index: usize) -> String {
+    format!("/net/reactivated/Fprint/Device/{index}")
+}
 
 #[async_std::main]
 async fn main() -> Result<(), Box<dyn Error>> {
@@ -59,48 +82,119 @@ async fn main() -> Result<(), Box<dyn Error>> {
         .into());
     }
 
-    enum CompatibleDrivers {
-        None,
-        One(usize),
-        Multiple(Vec<usize>),
+    let compatible = oks
+        .into_iter()
+        .map(|(_index, compatible)| compatible)
+        .collect::<Vec<_>>();
+    info!(
+        "Compatible drivers found: {:?}",
+        drivers
+            .iter()
+            .zip(&compatible)
+            .filter(|(_driver, compatible)| **compatible)
+            .map(|(driver, _compatible)| driver.name)
+            .collect::<Vec<_>>()
+    );
+    let templates_dir = get_templates_dir()?;
+    info!("Storing templates in {templates_dir}");
+    let store = TemplateStore::open(
+        &templates_dir,
+        &get_storage_key_file()?,
+        get_config()?.encrypt_templates,
+    )
+    .await?;
+    let key_provider = FileKeyProvider {
+        path: get_seed_file(&templates_dir).into(),
+        legacy: has_legacy_seed_templates(&store).await,
+    };
+    if key_provider.legacy {
+        warn!("Templates that only work with the legacy seed exist. If there is no seed yet, the legacy seed will be used (without saving it) so that they keep working. Run `rust-fp new-seed` to stop using it.");
+    }
+    let key_provider: Arc<dyn KeyProvider> = Arc::new(key_provider);
+    // Every driver gets a device, so that sensors that are plugged in later can be used.
+    // A sensor that can't be opened doesn't stop the others from being used.
+    let mut shared_drivers = vec![];
+    for (driver, compatible) in drivers.iter().zip(compatible) {
+        let opened_driver = match compatible {
+            true => {
+                info!("Opening {}.", driver.name);
+                match (driver.open_and_init)(key_provider.clone()).await {
+                    Ok(opened_driver) => {
+                        info!("Opened {}.", driver.name);
+                        Some(opened_driver)
+                    }
+                    Err(e) => {
+                        warn!("Error opening {}: {e}", driver.name);
+                        None
+                    }
+                }
+            }
+            false => None,
+        };
+        shared_drivers.push(SharedDriver::new(driver.name, opened_driver));
     }
-    let compatible_drivers = {
-        let compatible_drivers = oks
-            .into_iter()
-            .filter_map(|(index, compatible)| match compatible {
-                true => Some(index),
-                false => None,
-            })
-            .collect::<Vec<_>>();
-        match compatible_drivers.len() {
-            0 => CompatibleDrivers::None,
-            1 => CompatibleDrivers::One(compatible_drivers[0]),
-            2.. => CompatibleDrivers::Multiple(compatible_drivers),
+    let default_device = match shared_drivers
+        .iter()
+        .position(|driver| driver.is_available())
+    {
+        Some(index) => index,
+        None => {
+            warn!("No fingerprint sensors are available. Waiting for one to be plugged in.");
+            0
         }
     };
-    let driver = match compatible_drivers {
-        CompatibleDrivers::None => Err("No compatible drivers"),
-        CompatibleDrivers::One(driver) => Ok(&drivers[driver]),
-        CompatibleDrivers::Multiple(_drivers) => Err("Too many compatible drivers"),
-    }?;
-    info!("Compatible driver found: {}", driver.name);
-    info!("Opening driver.");
-    let driver = (driver.open_and_init)().await?;
-    info!("Opened driver.");
     info!("Starting dbus interface");
-    let _connection = Builder::system()?
+    let mut builder = Builder::system()?
         .name("org.rust_fp.RustFp")?
+        .name("net.reactivated.Fprint")?;
+    let mut devices = vec![];
+    let mut fprint_devices = vec![];
+    for (index, driver) in shared_drivers.iter().enumerate() {
+        let rust_fp = RustFp::new(driver.clone());
+        let rust_fp2 = || RustFp2 {
+            rust_fp: rust_fp.clone(),
+            store: store.clone(),
+            key_provider: key_provider.clone(),
+        };
+        let path = OwnedObjectPath::try_from(get_device_path(index))?;
+        builder = builder
+            .serve_at(path.clone(), rust_fp.clone())?
+            .serve_at(path.clone(), rust_fp2())?;
+        if index == default_device {
+            builder = builder
+                .serve_at(MANAGER_PATH, rust_fp.clone())?
+                .serve_at(MANAGER_PATH, rust_fp2())?;
+        }
+        let fprint_path = OwnedObjectPath::try_from(get_fprint_device_path(index))?;
+        builder = builder.serve_at(
+            fprint_path.clone(),
+            Device::new(driver.clone(), store.clone()),
+        )?;
+        devices.push((path, driver.clone()));
+        fprint_devices.push((fprint_path, driver.clone()));
+    }
+    let connection = builder
         .serve_at(
-            "/org/rust_fp/RustFp",
-            RustFp {
-                driver,
-                enrolling_id: Default::default(),
+            MANAGER_PATH,
+            manager_dbus::Manager {
+                devices,
+                default_device,
+            },
+        )?
+        .serve_at(
+            "/net/reactivated/Fprint/Manager",
+            Manager {
+                devices: fprint_devices,
+                default_device,
             },
         )?
         .build()
         .await?;
 
-    loop {
-        thread::park();
-    }
+    join(
+        watch_devices(&drivers, &shared_drivers, key_provider),
+        watch_sleep(&connection, &shared_drivers),
+    )
+    .await;
+    Ok(())
 }
@@ -0,0 +1,70 @@
+use async_std::task::spawn;
+use log::{info, warn};
+use rust_fp_common::shared_driver::SharedDriver;
+use zbus::export::futures_util::StreamExt;
+use zbus::zvariant::OwnedFd;
+use zbus::{proxy, Connection};
+
+#[proxy(
+    interface = "org.freedesktop.login1.Manager",
+    default_service = "org.freedesktop.login1",
+    default_path = "/org/freedesktop/login1"
+)]
+trait LoginManager {
+    /// logind waits until the returned file is closed before doing `what`
+    fn inhibit(&self, what: &str, who: &str, why: &str, mode: &str) -> zbus::Result<OwnedFd>;
+
+    /// `start` is `true` right before the computer sleeps, and `false` after it wakes up
+    #[zbus(signal)]
+    fn prepare_for_sleep(&self, start: bool) -> zbus::Result<()>;
+}
+
+/// Makes logind wait for the sensors to be ready for sleep before sleeping
+async fn inhibit_sleep(manager: &LoginManagerProxy<'_>) -> zbus::Result<OwnedFd> {
+    manager
+        .inhibit(
+            "sleep",
+            "rust-fp",
+            "Preparing fingerprint sensors for sleep",
+            "delay",
+        )
+        .await
+}
+
+async fn try_watch_sleep(connection: &Connection, drivers: &[SharedDriver]) -> zbus::Result<()> {
+    let manager = LoginManagerProxy::new(connection).await?;
+    let mut signals = manager.receive_prepare_for_sleep().await?;
+    let mut inhibitor = Some(inhibit_sleep(&manager).await?);
+    // Nothing can use the drivers while the computer is sleeping
+    let mut guards = Vec::new();
+    while let Some(signal) = signals.next().await {
+        match signal.args()?.start {
+            true => {
+                info!("Preparing fingerprint sensors for sleep");
+                for driver in drivers {
+                    guards.push(driver.suspend().await);
+                }
+                // Closing the file lets the computer sleep
+                inhibitor.take();
+            }
+            false => {
+                info!("Getting fingerprint sensors ready after waking up");
+                // Each sensor becomes usable as soon as it's ready, without waiting for the others or for logind
+                for (driver, guard) in drivers.iter().zip(guards.drain(..)) {
+                    let driver = driver.clone();
+                    spawn(async move { driver.resume(guard).await });
+                }
+                inhibitor = Some(inhibit_sleep(&manager).await?);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Gets sensors ready for the computer sleeping, and gets them ready to be used again as soon as it wakes up,
+/// so that the first match after opening the lid isn't slower than any other. Never returns if logind is running.
+pub async fn watch_sleep(connection: &Connection, drivers: &[SharedDriver]) {
+    if let Err(e) = try_watch_sleep(connection, drivers).await {
+        warn!("Not preparing fingerprint sensors for sleep: {e}");
+    }
+}
@@ -12,14 +12,9 @@ use pam::constants::{PamFlag, PamResultCode, PAM_ERROR_MSG};
 use pam::conv::Conv;
 use pam::module::{PamHandle, PamHooks};
 use pam::pam_try;
-use pollster::block_on;
-use postcard::from_bytes;
 use zbus::blocking::Connection;
 
-use rust_fp::fingerprint_driver::{MatchOutput, MatchedOutput};
-use rust_fp_common::get_templates::get_templates;
-use rust_fp_common::rust_fp_dbus::RustFpProxyBlocking;
-use rust_fp_common::set_templates::set_templates;
+use rust_fp_common::rust_fp2_dbus::{MatchStatus, RustFp2ProxyBlocking};
 
 use crate::wait_until_unlock::wait_until_unlock;
 
@@ -28,9 +23,20 @@ mod wait_until_unlock;
 struct RustFpPam;
 pam::pam_hooks!(RustFpPam);
 
+/// Makes the daemon stop waiting for a finger, so that the sensor isn't stuck matching for us after we exit
+fn cancel_matching() {
+    let _ = Connection::system().and_then(|connection| {
+        RustFp2ProxyBlocking::new(&connection)?.cancel()?;
+        Ok(())
+    });
+}
+
 impl PamHooks for RustFpPam {
     // This function performs the task of authenticating the user.
     fn sm_authenticate(pamh: &mut PamHandle, _args: Vec<&CStr>, _flags: PamFlag) -> PamResultCode {
+        // The daemon has the templates, and it only matches the user's fingers
+        let username = pam_try!(pamh.get_user(None));
+
         enum Message {
             Error(String),
             Result(PamResultCode),
@@ -44,6 +50,7 @@ impl PamHooks for RustFpPam {
             move || {
                 // Useful for debugging
                 // Command::new("play").arg("https://www.myinstants.com/media/sounds/sudden-suspense-sound-effect.mp3").output().unwrap();
+                cancel_matching();
                 tx.send(Message::Result(PAM_ABORT)).unwrap();
             }
         });
@@ -54,6 +61,7 @@ impl PamHooks for RustFpPam {
                 if wait_until_unlock().is_ok() {
                     // Useful for debugging
                     // Command::new("play").arg("https://www.myinstants.com/media/sounds/sudden-suspense-sound-effect.mp3").output().unwrap();
+                    cancel_matching();
                     tx.send(Message::Result(PAM_ABORT)).unwrap();
                 }
             }
@@ -65,66 +73,76 @@ impl PamHooks for RustFpPam {
                 let authenticate = {
                     let tx = tx.clone();
                     move || -> PamResultCode {
-                        let mut templates = block_on(get_templates()).unwrap();
-                        if !templates.is_empty() {
-                            let connection = Connection::system().unwrap();
-                            let proxy = RustFpProxyBlocking::new(&connection).unwrap();
-                            let templates_vec = templates.iter().collect::<Vec<_>>();
-                            let max_attempts = 5;
-                            for attempt in 0..max_attempts {
-                                let output: MatchOutput = from_bytes(
-                                    &proxy
-                                        .match_templates(
-                                            templates_vec
-                                                .iter()
-                                                .map::<Vec<u8>, _>(|(_k, v)| v.to_vec())
-                                                .collect(),
-                                        )
-                                        .unwrap(),
-                                )
+                        let connection = Connection::system().unwrap();
+                        let proxy = RustFp2ProxyBlocking::new(&connection).unwrap();
+                        // Otherwise a user without fingers would have to touch the sensor before finding out
+                        match proxy.has_fingers(&username) {
+                            Ok(true) => {}
+                            Ok(false) => {
+                                tx.send(Message::Error(
+                                    "No fingers are enrolled on this device. Not matching.".into(),
+                                ))
                                 .unwrap();
-                                match output {
-                                    MatchOutput::Match(MatchedOutput {
-                                        index,
-                                        updated_template,
-                                    }) => {
-                                        let matched_label = templates_vec[index].0;
-                                        println!("Matched: {matched_label}.");
-                                        if let Some(updated_template) = updated_template {
-                                            println!(
-                                                "Template was updated. Saving updated template..."
-                                            );
-                                            templates
-                                                .insert(matched_label.to_owned(), updated_template);
-                                            block_on(set_templates(&templates)).unwrap();
-                                            println!("Saved updated template");
-                                        }
-                                        return PAM_SUCCESS;
-                                    }
-                                    MatchOutput::NoMatch(error) => {
-                                        let remaining_attempts = max_attempts - attempt - 1;
+                                // Like a wrong finger, so that the next module (like the password) is tried
+                                return PAM_AUTH_ERR;
+                            }
+                            Err(e) => {
+                                tx.send(Message::Error(format!("Not matching: {e}")))
+                                    .unwrap();
+                                return PAM_ABORT;
+                            }
+                        }
+                        let max_attempts = 5;
+                        // Waiting for a finger before matching means that attempts aren't used up without a finger
+                        let finger_detect = proxy
+                            .get_capabilities()
+                            .is_ok_and(|capabilities| capabilities.finger_detect);
+                        for attempt in 0..max_attempts {
+                            if finger_detect {
+                                if let Err(e) = proxy.wait_finger_down() {
+                                    tx.send(Message::Error(format!("Not matching: {e}")))
+                                        .unwrap();
+                                    return PAM_ABORT;
+                                }
+                            }
+                            let output = match proxy.verify(&username) {
+                                Ok(output) => output,
+                                // Matching was cancelled because we are aborting, or the daemon failed
+                                Err(e) => {
+                                    tx.send(Message::Error(format!("Not matching: {e}")))
+                                        .unwrap();
+                                    return PAM_ABORT;
+                                }
+                            };
+                            match output.status {
+                                MatchStatus::Match => {
+                                    println!("Matched: {}.", output.label);
+                                    return PAM_SUCCESS;
+                                }
+                                status => {
+                                    let remaining_attempts = max_attempts - attempt - 1;
+                                    tx.send(Message::Error(format!(
+                                        "No match. {remaining_attempts} attempts remaining."
+                                    )))
+                                    .unwrap();
+                                    if status != MatchStatus::NoMatch {
                                         tx.send(Message::Error(format!(
-                                            "No match. {remaining_attempts} attempts remaining."
+                                            "Error matching: {status:?}"
                                         )))
                                         .unwrap();
-                                        if let Some(error) = error {
-                                            tx.send(Message::Error(format!(
-                                                "Error matching: {error:?}"
-                                            )))
-                                            .unwrap();
-                                        }
+                                    }
+                                    // Otherwise a finger that stays on the sensor would use up the next attempt too
+                                    if finger_detect && proxy.wait_finger_up().is_err() {
+                                        return PAM_ABORT;
                                     }
                                 }
                             }
-                            PAM_AUTH_ERR
-                        } else {
-                            tx.send(Message::Error("No templates saved. Not matching.".into()))
-                                .unwrap();
-                            PAM_AUTH_ERR
                         }
+                        PAM_AUTH_ERR
                     }
                 };
-                tx.send(Message::Result(authenticate())).unwrap();
+                // The receiver is gone if we already aborted
+                let _ = tx.send(Message::Result(authenticate()));
             }
         });
         let conv = match pamh.get_item::<Conv>() {
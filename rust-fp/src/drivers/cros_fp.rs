@@ -1,15 +1,13 @@
-use std::{
-    collections::HashSet,
-    error::Error,
-    hash::{DefaultHasher, Hash, Hasher},
-    io::{self, ErrorKind},
-    time::Duration,
-};
+use std::{cmp::Reverse, fmt::Debug, io::ErrorKind, sync::Arc, time::Duration};
 
-use async_std::{fs::File, task::sleep};
+use async_std::{
+    fs::{read_to_string, File},
+    future::timeout,
+    task::sleep,
+};
 use crosec::{
     commands::{
-        fp_download::{fp_download_template, FpTemplate},
+        fp_download::{fp_download_frame, fp_download_template, FpTemplate},
         fp_get_encryption_status::{fp_get_encryption_status, FpEncryptionStatus},
         fp_info::{fp_info, EcResponseFpInfo},
         fp_mode::{fp_mode, FpMode},
@@ -31,12 +29,144 @@ use crosec::{
     CROS_FP_PATH,
 };
 use futures::future::BoxFuture;
+use sha2::{Digest, Sha256};
 
 use crate::drivers::GetFingerprintDriver;
 use crate::fingerprint_driver::{
-    EnrollStepError, EnrollStepOutput, EnrollStepResult, FingerprintDriver, MatchOutput,
-    MatchedOutput, NoMatchError, OpenedFingerprintDriver,
+    CancelSignal, Canceller, Capabilities, CaptureType, DeviceInfo, DriverError, EnrollStepError,
+    EnrollStepOutput, EnrollStepResult, FingerDownListener, FingerDownSignal, FingerprintDriver,
+    Image, MatchOutput, MatchedOutput, NoMatchError, OpenedFingerprintDriver, ScanType,
+    SelfTestCheck, SelfTestReport, SensorId, TemplateId,
 };
+use crate::key_provider::{KeyProvider, LEGACY_SEED};
+
+/// The context that older versions of rust-fp always used, together with [`LEGACY_SEED`]
+const LEGACY_CONTEXT: [u8; 32] = [0xaa; 32];
+/// The cros_ec kernel driver shows the FPMCU's firmware versions here
+const FIRMWARE_VERSION_PATH: &str = "/sys/class/chromeos/cros_fp/version";
+/// Chromebook sensors finish enrolling after 5 good touches
+const ENROLL_STAGES: u32 = 5;
+
+/// How long capturing an image without a finger can take
+const CAPTURE_TIMEOUT: Duration = Duration::from_secs(5);
+/// The capture type goes in the top bits of the mode when setting [`FpMode::Capture`]
+const CAPTURE_TYPE_SHIFT: u32 = 28;
+/// The self test fails if more pixels than this are bad. This is a heuristic, not a limit from the sensor's datasheet.
+const MAX_BAD_PIXELS: usize = 10;
+/// How far a pixel in the reset test can be from the median, out of 255. Also a heuristic.
+const MAX_RESET_PIXEL_DEVIATION: u32 = 35;
+
+/// The FPMCU's capture types
+#[derive(Debug, Clone, Copy)]
+enum FpCaptureType {
+    SimpleImage = 1,
+    /// Checkerboard
+    Pattern0 = 2,
+    /// Inverted checkerboard
+    Pattern1 = 3,
+    QualityTest = 4,
+    ResetTest = 5,
+}
+
+impl FpCaptureType {
+    fn needs_finger(self) -> bool {
+        match self {
+            Self::SimpleImage | Self::QualityTest => true,
+            Self::Pattern0 | Self::Pattern1 | Self::ResetTest => false,
+        }
+    }
+}
+
+impl From<CaptureType> for FpCaptureType {
+    fn from(capture_type: CaptureType) -> Self {
+        match capture_type {
+            CaptureType::Simple => Self::SimpleImage,
+            CaptureType::Pattern => Self::Pattern0,
+            CaptureType::QualityTest => Self::QualityTest,
+            CaptureType::ResetTest => Self::ResetTest,
+        }
+    }
+}
+
+/// The lower bits of [`EcResponseFpInfo::errors`] are the number of dead pixels
+const FP_ERROR_DEAD_PIXELS_MASK: u16 = 0x3ff;
+/// The sensor didn't count dead pixels
+const FP_ERROR_DEAD_PIXELS_UNKNOWN: u16 = 0x3ff;
+/// Error flags in [`EcResponseFpInfo::errors`]
+const FP_ERRORS: [(u16, &str); 4] = [
+    (1 << 12, "no interrupt"),
+    (1 << 13, "SPI communication error"),
+    (1 << 14, "bad hardware id"),
+    (1 << 15, "initialization failed"),
+];
+
+/// Gets the read-only and read-write firmware versions out of the sysfs version file,
+/// like `RO nocturne_fp_v2.2.64-58cf5974e, RW nocturne_fp_v2.2.144-7a08e07eb`
+fn parse_firmware_version(version_file: &str) -> String {
+    version_file
+        .lines()
+        .filter_map(|line| {
+            let (name, version) = line.split_once(':')?;
+            let name = name.strip_suffix(" version")?;
+            matches!(name, "RO" | "RW").then(|| format!("{name} {}", version.trim()))
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// The value of every pixel. Pixels with more than 8 bits are 2 little endian bytes.
+fn pixel_values(image: &Image) -> Vec<u32> {
+    match image.bits_per_pixel > 8 {
+        true => image
+            .pixels
+            .chunks_exact(2)
+            .map(|pixel| u16::from_le_bytes([pixel[0], pixel[1]]).into())
+            .collect(),
+        false => image.pixels.iter().map(|&pixel| pixel.into()).collect(),
+    }
+}
+
+fn max_pixel_value(image: &Image) -> u32 {
+    (1 << image.bits_per_pixel) - 1
+}
+
+/// Pixels that barely change between the checkerboard and the inverted checkerboard are stuck
+fn count_stuck_pixels(pattern0: &Image, pattern1: &Image) -> usize {
+    let min_difference = max_pixel_value(pattern0) / 4;
+    pixel_values(pattern0)
+        .into_iter()
+        .zip(pixel_values(pattern1))
+        .filter(|&(pixel0, pixel1)| pixel0.abs_diff(pixel1) < min_difference)
+        .count()
+}
+
+/// Right after resetting, every pixel should be about the same
+fn count_reset_outliers(image: &Image) -> usize {
+    let mut pixels = pixel_values(image);
+    pixels.sort_unstable();
+    let median = pixels.get(pixels.len() / 2).copied().unwrap_or_default();
+    let max_deviation = max_pixel_value(image) * MAX_RESET_PIXEL_DEVIATION / 255;
+    pixels
+        .into_iter()
+        .filter(|pixel| pixel.abs_diff(median) > max_deviation)
+        .count()
+}
+
+fn check(name: &str, passed: bool, details: String) -> SelfTestCheck {
+    SelfTestCheck {
+        name: name.into(),
+        passed,
+        details,
+    }
+}
+
+/// Converts the error of an EC command
+fn ec_command_error<E: Debug>(command: &str) -> impl FnOnce(E) -> DriverError + '_ {
+    move |e| DriverError::EcCommand {
+        command: command.into(),
+        error: format!("{e:?}"),
+    }
+}
 
 pub struct CrosFp;
 
@@ -44,6 +174,7 @@ impl GetFingerprintDriver for CrosFp {
     fn get_driver() -> FingerprintDriver {
         FingerprintDriver {
             name: "Chromebook",
+            device_nodes: &[CROS_FP_PATH],
             is_compatible: Box::new(|| {
                 Box::pin(async {
                     match File::open(CROS_FP_PATH).await {
@@ -55,9 +186,9 @@ impl GetFingerprintDriver for CrosFp {
                     }
                 })
             }),
-            open_and_init: Box::new(|| {
-                Box::pin(async {
-                    let opened_cros_fp = OpenedCrosFp::open_and_init().await?;
+            open_and_init: Box::new(|key_provider| {
+                Box::pin(async move {
+                    let opened_cros_fp = OpenedCrosFp::open_and_init(key_provider).await?;
                     Ok(Box::new(opened_cros_fp) as Box<dyn OpenedFingerprintDriver>)
                 })
             }),
@@ -65,122 +196,471 @@ impl GetFingerprintDriver for CrosFp {
     }
 }
 
+/// A template that is loaded on the FPMCU
+#[derive(Clone)]
+struct Slot {
+    /// So that templates that are already loaded aren't uploaded again
+    id: TemplateId,
+    /// Kept so that the template can be uploaded again when other slots are evicted
+    template: Vec<u8>,
+    /// When the template was last matched, from [`OpenedCrosFp::uses`]. 0 if it wasn't matched since it was loaded.
+    last_used: u64,
+}
+
+/// What [`OpenedCrosFp::load_templates`] needs to do. Templates are indexes into the templates to load.
+#[derive(Debug, PartialEq, Eq)]
+enum LoadPlan {
+    /// Every template is already loaded
+    Loaded,
+    /// The missing templates fit in the free slots
+    Append { missing: Vec<usize> },
+    /// The FPMCU is reset, and then the kept slots (indexes into the slots) and the missing templates are uploaded
+    Reset {
+        keep: Vec<usize>,
+        missing: Vec<usize>,
+    },
+}
+
+/// Decides which templates to upload, and which loaded slots to keep if there isn't enough space for every template.
+/// The given templates are always loaded. Other loaded templates only keep the space that is left, most recently matched first.
+fn plan_load(
+    slots: &[Slot],
+    same_context: bool,
+    ids: &[TemplateId],
+    max_templates: usize,
+) -> Result<LoadPlan, DriverError> {
+    // Templates from another user can't be kept
+    let slots = match same_context {
+        true => slots,
+        false => &[],
+    };
+    // Duplicate templates only take up one slot
+    let mut unique = Vec::<usize>::new();
+    for (index, id) in ids.iter().enumerate() {
+        if !unique.iter().any(|&unique_index| ids[unique_index] == *id) {
+            unique.push(index);
+        }
+    }
+    if unique.len() > max_templates {
+        return Err(DriverError::TooManyTemplates {
+            count: unique.len(),
+            max: max_templates,
+        });
+    }
+    let missing = unique
+        .into_iter()
+        .filter(|&index| !slots.iter().any(|slot| slot.id == ids[index]))
+        .collect::<Vec<_>>();
+    Ok(match (same_context, missing.is_empty()) {
+        (true, true) => LoadPlan::Loaded,
+        (true, false) if slots.len() + missing.len() <= max_templates => {
+            LoadPlan::Append { missing }
+        }
+        _ => {
+            let (mut keep, mut others): (Vec<_>, Vec<_>) =
+                (0..slots.len()).partition(|&index| ids.contains(&slots[index].id));
+            others.sort_by_key(|&index| Reverse(slots[index].last_used));
+            others.truncate(max_templates.saturating_sub(keep.len() + missing.len()));
+            keep.extend(others);
+            LoadPlan::Reset { keep, missing }
+        }
+    })
+}
+
 pub struct OpenedCrosFp {
     file: File,
     protocol_info: EcResponseGetProtocolInfo,
-    loaded_templates_hashes: Vec<u64>,
+    /// The templates loaded on the FPMCU, in the order of its slots
+    slots: Vec<Slot>,
+    /// Counts matches, to find the least recently matched slot
+    uses: u64,
     fp_info: EcResponseFpInfo,
+    /// Empty if the kernel driver doesn't show it
+    firmware_version: String,
+    cancel_signal: CancelSignal,
+    finger_down_signal: FingerDownSignal,
+    seed: [u8; 32],
+    /// The context that was set, which is different for every user. Templates only work with the context they were enrolled with.
+    context: Option<[u8; 32]>,
+    /// If we set the seed before. If the seed is not set anymore, the sensor was reset.
+    seed_was_set: bool,
+    reset_detected: bool,
 }
 
 impl OpenedCrosFp {
-    async fn open_and_init() -> io::Result<Self> {
+    async fn open_and_init(key_provider: Arc<dyn KeyProvider>) -> Result<Self, DriverError> {
+        let seed = key_provider.get_seed().await?;
         let mut file = File::open(CROS_FP_PATH).await?;
-        fp_mode(&mut file, FpMode::Reset as u32).unwrap();
-        fp_mode(&mut file, FpMode::ResetSensor as u32).unwrap();
-        let protocol_info = get_protocol_info(&mut file).unwrap();
-        let fp_info = fp_info(&mut file).unwrap();
+        fp_mode(&mut file, FpMode::Reset as u32).map_err(ec_command_error("fp_mode Reset"))?;
+        fp_mode(&mut file, FpMode::ResetSensor as u32)
+            .map_err(ec_command_error("fp_mode ResetSensor"))?;
+        let protocol_info =
+            get_protocol_info(&mut file).map_err(ec_command_error("get_protocol_info"))?;
+        let fp_info = fp_info(&mut file).map_err(ec_command_error("fp_info"))?;
+        let firmware_version = read_to_string(FIRMWARE_VERSION_PATH)
+            .await
+            .map(|version_file| parse_firmware_version(&version_file))
+            .unwrap_or_default();
         Ok(Self {
             file,
-            loaded_templates_hashes: Default::default(),
+            slots: Default::default(),
+            uses: 0,
             protocol_info,
             fp_info,
+            firmware_version,
+            cancel_signal: Default::default(),
+            finger_down_signal: Default::default(),
+            seed,
+            context: None,
+            seed_was_set: false,
+            reset_detected: false,
         })
     }
 
-    async fn wait_until_duration_after_fpmcu_boot(&mut self, duration: Duration) {
-        let uptime_info = ec_cmd_get_uptime_info(&mut self.file).unwrap();
+    async fn wait_until_duration_after_fpmcu_boot(
+        &mut self,
+        duration: Duration,
+    ) -> Result<(), DriverError> {
+        let uptime_info =
+            ec_cmd_get_uptime_info(&mut self.file).map_err(ec_command_error("get_uptime_info"))?;
         if let Some(sleep_duration) = duration.checked_sub(Duration::from_millis(
             uptime_info.time_since_ec_boot_ms.into(),
         )) {
             sleep(sleep_duration).await;
         }
+        Ok(())
     }
 
     /// Sets the seed if it hasn't been set. Clears hashes if seed got un-set,
     /// which indicates that the FP sensor restarted, which happens during suspend.
-    async fn ensure_seed_is_set(&mut self) {
+    async fn ensure_seed_is_set(&mut self) -> Result<(), DriverError> {
         // This number was found through trial and error. 1250 failed, but there may be some room for improvement after even more trial and error.
         self.wait_until_duration_after_fpmcu_boot(Duration::from_millis(1350))
-            .await;
-        let status = fp_get_encryption_status(&mut self.file).unwrap();
+            .await?;
+        let status = fp_get_encryption_status(&mut self.file)
+            .map_err(ec_command_error("fp_get_encryption_status"))?;
         if status.status & (FpEncryptionStatus::SeedSet as u32) == 0 {
-            // Set the seed to "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa" so that it can easily be typed manually too.
-            // I'm pretty sure the tradition of using this seed was made by WeirdTreeThing.
-            fp_set_seed(&mut self.file, [b'a'; 32]).unwrap();
-            self.loaded_templates_hashes.clear();
+            // The seed can only be set once until the FPMCU reboots.
+            // If it was set before we opened the sensor, we assume it was set to the same seed.
+            fp_set_seed(&mut self.file, self.seed).map_err(ec_command_error("fp_set_seed"))?;
+            self.slots.clear();
+            self.context = None;
+            if self.seed_was_set {
+                self.reset_detected = true;
+            }
+            self.seed_was_set = true;
+        }
+        Ok(())
+    }
+
+    /// The context for a user, so that templates enrolled for one user can't be used for another user
+    fn user_context(&self, user: u32) -> [u8; 32] {
+        match self.seed == LEGACY_SEED {
+            // Templates enrolled with the legacy seed were all enrolled with the legacy context
+            true => LEGACY_CONTEXT,
+            false => Sha256::new()
+                .chain_update(b"rust-fp user context")
+                .chain_update(user.to_le_bytes())
+                .finalize()
+                .into(),
         }
     }
 
     /// Sets the context. The context must be set before enrolling and uploading
     /// The context gets reset when the sensor gets reset
     /// It is possible that the seed doesn't get reset but the context does
-    fn set_context(&mut self) {
-        fp_set_context(&mut self.file, [0xaa; 32]).unwrap();
+    fn set_context(&mut self, context: [u8; 32]) -> Result<(), DriverError> {
+        fp_set_context(&mut self.file, context).map_err(ec_command_error("fp_set_context"))?;
+        self.context = Some(context);
         // Setting context always clears templates, even if the context was previously set to the same value
-        self.loaded_templates_hashes.clear();
+        self.slots.clear();
+        Ok(())
+    }
+
+    /// Gets the FPMCU back to a known state with no templates loaded
+    fn reset(&mut self, context: [u8; 32]) -> Result<(), DriverError> {
+        fp_mode(&mut self.file, FpMode::Reset as u32).map_err(ec_command_error("fp_mode Reset"))?;
+        self.set_context(context)
     }
 
-    fn check_if_templates_got_cleared(&mut self) {
-        if !self.loaded_templates_hashes.is_empty() {
-            let info = fp_info(&mut self.file).unwrap();
-            let stored_loaded_templates_count = self.loaded_templates_hashes.len() as u16;
+    fn check_if_templates_got_cleared(&mut self, context: [u8; 32]) -> Result<(), DriverError> {
+        if !self.slots.is_empty() {
+            let info = fp_info(&mut self.file).map_err(ec_command_error("fp_info"))?;
+            let stored_loaded_templates_count = self.slots.len() as u16;
             let actual_loaded_templates_count = info.template_valid;
             if actual_loaded_templates_count == stored_loaded_templates_count {
                 // Assume templates have not changed
             } else if info.template_valid == 0 {
-                self.loaded_templates_hashes.clear();
+                self.slots.clear();
+                // The context may have been cleared too
+                self.context = None;
+                self.reset_detected = true;
             } else {
-                // Something is wrong. Panic instead of causing undefined behaviour
-                panic!("Expected {stored_loaded_templates_count} or 0 templates to be loaded, but actually {actual_loaded_templates_count} templates are loaded.")
+                // We don't know which templates are loaded, so start over with no templates loaded
+                self.reset(context).map_err(|e| {
+                    DriverError::TemplateDesync(format!("Expected {stored_loaded_templates_count} or 0 templates to be loaded, but actually {actual_loaded_templates_count} templates are loaded. Resetting failed: {e}"))
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    fn next_use(&mut self) -> u64 {
+        self.uses += 1;
+        self.uses
+    }
+
+    /// Uploads a template into the next free slot
+    fn upload_slot(&mut self, slot: Slot) -> Result<(), DriverError> {
+        fp_upload_template(
+            &mut self.file,
+            &self.protocol_info,
+            &self.fp_info,
+            &unsafe { FpTemplate::from_vec_unchecked(slot.template.clone()) },
+        )
+        .map_err(ec_command_error("fp_upload_template"))?;
+        self.slots.push(slot);
+        Ok(())
+    }
+
+    /// Makes sure that the templates are loaded, uploading only the ones that aren't loaded yet.
+    /// Other templates stay loaded if there is space for them, since they are probably going to be matched again.
+    /// The FPMCU can't remove a single template, so if there isn't enough space,
+    /// it is reset and the least recently matched templates are left out when uploading again.
+    async fn load_templates(
+        &mut self,
+        context: [u8; 32],
+        templates: &[Vec<u8>],
+        ids: &[TemplateId],
+    ) -> Result<(), DriverError> {
+        let (reupload, missing) = match plan_load(
+            &self.slots,
+            self.context == Some(context),
+            ids,
+            self.fp_info.template_max as usize,
+        )? {
+            LoadPlan::Loaded => return Ok(()),
+            LoadPlan::Append { missing } => {
+                // The new templates go in the free slots
+                fp_mode(&mut self.file, FpMode::Reset as u32)
+                    .map_err(ec_command_error("fp_mode Reset"))?;
+                (Vec::new(), missing)
+            }
+            LoadPlan::Reset { keep, missing } => {
+                let mut slots = std::mem::take(&mut self.slots)
+                    .into_iter()
+                    .map(Some)
+                    .collect::<Vec<_>>();
+                let reupload = keep
+                    .into_iter()
+                    .filter_map(|index| slots[index].take())
+                    .collect::<Vec<_>>();
+                self.reset(context)?;
+                (reupload, missing)
             }
+        };
+        // Without waiting a bit, the template uploading can fail.
+        // Maybe 10ms is enough, idk what is the smallest amount that works 99% of the time.
+        sleep(Duration::from_millis(10)).await;
+        for slot in reupload {
+            self.upload_slot(slot)?;
+        }
+        for index in missing {
+            self.upload_slot(Slot {
+                id: ids[index],
+                template: templates[index].clone(),
+                last_used: 0,
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Puts the FPMCU in finger down or finger up mode and waits for the event.
+    /// The enroll session bit is kept, because clearing it would end the enroll session.
+    async fn wait_finger(&mut self, down: bool) -> Result<(), DriverError> {
+        self.cancel_signal.clear();
+        let enroll_session = fp_mode(&mut self.file, FpMode::DontChange as u32)
+            .map_err(ec_command_error("fp_mode DontChange"))?
+            & FpMode::EnrollSession as u32;
+        let (finger_mode, command) = match down {
+            true => (FpMode::FingerDown, "fp_mode FingerDown"),
+            false => (FpMode::FingerUp, "fp_mode FingerUp"),
+        };
+        fp_mode(&mut self.file, finger_mode as u32 | enroll_session)
+            .map_err(ec_command_error(command))?;
+        let event = match self
+            .cancel_signal
+            .or_cancelled(wait_event_async(
+                &mut self.file,
+                [EcMkbpEventType::Fingerprint],
+            ))
+            .await
+        {
+            Ok(event) => event?,
+            Err(cancelled) => {
+                // Stop waiting for the finger, going back to just the enroll session if there is one
+                fp_mode(&mut self.file, FpMode::Reset as u32 | enroll_session)
+                    .map_err(ec_command_error("fp_mode Reset"))?;
+                return Err(cancelled.into());
+            }
+        };
+        match event {
+            EcMkbpEvent::Fingerprint(event) => match (down, event.rust()) {
+                (true, EcMkbpEventFingerprintRust::FingerDown)
+                | (false, EcMkbpEventFingerprintRust::FingerUp) => Ok(()),
+                (_, fp_event) => Err(DriverError::UnexpectedEvent(format!("{fp_event:?}"))),
+            },
+            event => Err(DriverError::UnexpectedEvent(format!("{event:?}"))),
+        }
+    }
+
+    /// Captures an image and downloads it. Waits for a finger if the capture type needs one.
+    /// Captures an image. The FPMCU stays in capture mode after capturing, so it is put back in the mode it was in before.
+    async fn capture(&mut self, capture_type: FpCaptureType) -> Result<Image, DriverError> {
+        let previous_mode = fp_mode(&mut self.file, FpMode::DontChange as u32)
+            .map_err(ec_command_error("fp_mode DontChange"))?;
+        let result = self.capture_frame(capture_type).await;
+        fp_mode(&mut self.file, previous_mode).map_err(ec_command_error("fp_mode"))?;
+        result
+    }
+
+    async fn capture_frame(&mut self, capture_type: FpCaptureType) -> Result<Image, DriverError> {
+        fp_mode(
+            &mut self.file,
+            FpMode::Capture as u32 | (capture_type as u32) << CAPTURE_TYPE_SHIFT,
+        )
+        .map_err(ec_command_error("fp_mode Capture"))?;
+        let wait = wait_event_async(&mut self.file, [EcMkbpEventType::Fingerprint]);
+        let result = match self
+            .cancel_signal
+            .or_cancelled(async {
+                match capture_type.needs_finger() {
+                    true => Ok(wait.await),
+                    // Without a finger, the image is captured right away
+                    false => timeout(CAPTURE_TIMEOUT, wait)
+                        .await
+                        .map_err(|_| DriverError::Timeout),
+                }
+            })
+            .await
+        {
+            Ok(result) => result,
+            Err(cancelled) => Err(cancelled.into()),
+        };
+        match result?? {
+            EcMkbpEvent::Fingerprint(event) => match event.rust() {
+                EcMkbpEventFingerprintRust::ImageReady => Ok(()),
+                fp_event => Err(DriverError::UnexpectedEvent(format!("{fp_event:?}"))),
+            },
+            event => Err(DriverError::UnexpectedEvent(format!("{event:?}"))),
+        }?;
+        let mut pixels = fp_download_frame(&mut self.file, &self.fp_info, &self.protocol_info)
+            .map_err(ec_command_error("fp_frame"))?;
+        let image_size = self.fp_info.width as usize
+            * self.fp_info.height as usize
+            * (self.fp_info.bpp as usize).div_ceil(8);
+        if pixels.len() < image_size {
+            return Err(DriverError::Io(format!(
+                "Frame has {} bytes, but a {}x{} image with {} bits per pixel needs {image_size} bytes",
+                pixels.len(),
+                self.fp_info.width,
+                self.fp_info.height,
+                self.fp_info.bpp
+            )));
         }
+        pixels.truncate(image_size);
+        Ok(Image {
+            width: self.fp_info.width.into(),
+            height: self.fp_info.height.into(),
+            bits_per_pixel: self.fp_info.bpp as u8,
+            pixels,
+        })
     }
 }
 
 impl OpenedFingerprintDriver for OpenedCrosFp {
-    fn start_or_continue_enroll(&mut self) -> BoxFuture<EnrollStepResult> {
-        Box::pin(async {
-            self.ensure_seed_is_set().await;
-            self.check_if_templates_got_cleared();
-            // Clear templates if there are no more slots left
-            if self.loaded_templates_hashes.len() == self.fp_info.template_max as usize {
-                // TODO: We may need to set fp mode to Reset before doing this
-                self.set_context();
-            } else if self.loaded_templates_hashes.is_empty() {
+    fn canceller(&self) -> Canceller {
+        self.cancel_signal.canceller()
+    }
+
+    fn finger_down_listener(&self) -> Option<FingerDownListener> {
+        Some(self.finger_down_signal.listener())
+    }
+
+    fn start_or_continue_enroll(&mut self, user: u32) -> BoxFuture<'_, EnrollStepResult> {
+        Box::pin(async move {
+            self.cancel_signal.clear();
+            self.ensure_seed_is_set().await?;
+            let context = self.user_context(user);
+            self.check_if_templates_got_cleared(context)?;
+            // Clear templates if there are no more slots left.
+            // The context can only be set in reset mode, and the FPMCU may still be in match mode.
+            if self.slots.len() == self.fp_info.template_max as usize {
+                self.reset(context)?;
+            } else if self
+                .context
+                .is_some_and(|loaded_context| loaded_context != context)
+            {
+                // The loaded templates and any enroll session are for a different user
+                self.reset(context)?;
+            } else if self.slots.is_empty() {
                 // Unless we already started enrolling, set the context since it may not be set
-                let fp_mode = fp_mode(&mut self.file, FpMode::DontChange as u32).unwrap();
+                let fp_mode = fp_mode(&mut self.file, FpMode::DontChange as u32)
+                    .map_err(ec_command_error("fp_mode DontChange"))?;
                 match FpMode::from_repr(fp_mode) {
                     Some(FpMode::EnrollSession) => {
                         // Don't set context because we assume it is already set and you can't set context while enrolling
                     }
                     Some(FpMode::Reset) => {
-                        self.set_context();
+                        self.set_context(context)?;
                     }
                     _ => {
-                        // The fp should not be in any other mode. We can't set context unless it's reset
-                        panic!("Unknown fp mode: {fp_mode}")
+                        // The fp should not be in any other mode. We can't set context unless it's reset.
+                        self.reset(context)
+                            .map_err(|_e| DriverError::UnexpectedMode(fp_mode))?;
                     }
                 }
             }
+            // The FPMCU also tells us when it notices the finger, before it is done capturing it
             fp_mode(
                 &mut self.file,
-                FpMode::EnrollSession as u32 | FpMode::EnrollImage as u32,
+                FpMode::EnrollSession as u32
+                    | FpMode::EnrollImage as u32
+                    | FpMode::FingerDown as u32,
             )
-            .map_err(|_e| EnrollStepError::GenericError)?;
-            let data = match wait_event_async(&mut self.file, [EcMkbpEventType::Fingerprint])
-                .await
-                .map_err(|_e| EnrollStepError::GenericError)?
-            {
-                EcMkbpEvent::Fingerprint(event) => match event.rust() {
-                    EcMkbpEventFingerprintRust::Enroll(output) => Ok(output),
-                    _ => Err(EnrollStepError::GenericError),
-                },
-                _ => Err(EnrollStepError::GenericError),
-            }?;
+            .map_err(ec_command_error("fp_mode EnrollSession"))?;
+            let data = loop {
+                let event = match self
+                    .cancel_signal
+                    .or_cancelled(wait_event_async(
+                        &mut self.file,
+                        [EcMkbpEventType::Fingerprint],
+                    ))
+                    .await
+                {
+                    Ok(event) => event,
+                    Err(_cancelled) => {
+                        // Stop waiting for a finger. This also ends the enroll session.
+                        fp_mode(&mut self.file, FpMode::Reset as u32)
+                            .map_err(ec_command_error("fp_mode Reset"))?;
+                        return Err(EnrollStepError::Cancelled);
+                    }
+                };
+                match event.map_err(DriverError::from)? {
+                    EcMkbpEvent::Fingerprint(event) => match event.rust() {
+                        EcMkbpEventFingerprintRust::Enroll(output) => break output,
+                        EcMkbpEventFingerprintRust::FingerDown => self.finger_down_signal.notify(),
+                        fp_event => {
+                            return Err(DriverError::UnexpectedEvent(format!("{fp_event:?}")).into())
+                        }
+                    },
+                    event => return Err(DriverError::UnexpectedEvent(format!("{event:?}")).into()),
+                }
+            };
             match data.error {
                 None => Ok(match data.percentage {
                     100 => EnrollStepOutput::Complete({
-                        let enrolled_template_index = self.loaded_templates_hashes.len();
+                        let enrolled_template_index = self.slots.len();
                         let template = fp_download_template(
                             &mut self.file,
                             &self.fp_info,
@@ -188,143 +668,498 @@ impl OpenedFingerprintDriver for OpenedCrosFp {
                             enrolled_template_index,
                         );
                         let template_vec: Vec<u8> = template.into();
-                        let hash = {
-                            let mut hasher = DefaultHasher::default();
-                            template_vec.hash(&mut hasher);
-                            hasher.finish()
-                        };
-                        self.loaded_templates_hashes.push(hash);
+                        self.slots.push(Slot {
+                            id: TemplateId::new(&template_vec),
+                            template: template_vec.clone(),
+                            last_used: 0,
+                        });
                         template_vec
                     }),
                     percentage => EnrollStepOutput::InProgress(percentage),
                 }),
-                // TODO: Add more specific errors. I didn't add any because I never experienced them
                 Some(error) => Err(match error {
                     EcMkbpEventFingerprintEnrollError::LowQuality => EnrollStepError::LowQuality,
-                    _ => EnrollStepError::GenericError,
+                    EcMkbpEventFingerprintEnrollError::Immobile => EnrollStepError::Immobile,
+                    EcMkbpEventFingerprintEnrollError::Other
+                    | EcMkbpEventFingerprintEnrollError::Internal => EnrollStepError::GenericError,
                 }),
             }
         })
     }
 
-    fn get_max_templates(&mut self) -> anyhow::Result<usize> {
+    fn abort_enroll(&mut self) -> Result<(), DriverError> {
+        fp_mode(&mut self.file, FpMode::Reset as u32).map_err(ec_command_error("fp_mode Reset"))?;
+        Ok(())
+    }
+
+    fn get_max_templates(&mut self) -> Result<usize, DriverError> {
         Ok(self.fp_info.template_max as usize)
     }
 
+    fn sensor_id(&self) -> SensorId {
+        SensorId {
+            id: format!(
+                "{:08x}:{:08x}:{:08x}",
+                self.fp_info.vendor_id, self.fp_info.product_id, self.fp_info.model_id
+            ),
+            hardware_version: self.fp_info.version.to_string(),
+            firmware_version: self.firmware_version.clone(),
+        }
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            on_chip_matching: true,
+            max_templates: self.fp_info.template_max as usize,
+            template_size: Some(self.fp_info.template_size as usize),
+            image_capture: true,
+            finger_detect: true,
+            template_update_on_match: true,
+            scan_type: ScanType::Press,
+            enroll_stages: ENROLL_STAGES,
+        }
+    }
+
+    fn take_reset_detected(&mut self) -> bool {
+        std::mem::take(&mut self.reset_detected)
+    }
+
+    fn uses_legacy_seed(&self) -> bool {
+        self.seed == LEGACY_SEED
+    }
+
     fn match_templates<'a>(
         &'a mut self,
+        user: u32,
         templates: &'a [Vec<u8>],
-    ) -> BoxFuture<Result<MatchOutput, Box<dyn Error>>> {
+    ) -> BoxFuture<'a, Result<MatchOutput, DriverError>> {
         Box::pin(async move {
-            let hashes = templates
+            self.cancel_signal.clear();
+            let ids = templates
                 .iter()
-                .map(|template| {
-                    let mut hasher = DefaultHasher::default();
-                    template.hash(&mut hasher);
-                    hasher.finish()
-                })
+                .map(|template| TemplateId::new(template))
                 .collect::<Vec<_>>();
-            self.check_if_templates_got_cleared();
-            // FIXME: Figure out why the uploading is in a loop
-            let fingerprint_event = loop {
-                self.ensure_seed_is_set().await;
-                println!(
-                    "Hashes: {hashes:?}. Loaded hashes: {:?}",
-                    self.loaded_templates_hashes
-                );
-                if hashes.iter().collect::<HashSet<_>>()
-                    != self.loaded_templates_hashes.iter().collect()
-                {
-                    fp_mode(&mut self.file, FpMode::Reset as u32)
-                        .map_err(|_e| format!("Error doing {:?}", FpMode::Reset))?;
-                    self.set_context();
-                    // Without waiting a bit, the template uploading can fail.
-                    // Maybe 10ms is enough, idk what is the smallest amount that works 99% of the time.
-                    sleep(Duration::from_millis(10)).await;
-                    for template in templates {
-                        fp_upload_template(
-                            &mut self.file,
-                            &self.protocol_info,
-                            &self.fp_info,
-                            &unsafe { FpTemplate::from_vec_unchecked(template.to_vec()) },
-                        )
-                        .map_err(|e| format!("Error uploading template: {e:?}"))?;
-                    }
-                    self.loaded_templates_hashes.clone_from(&hashes);
-                }
-                fp_mode(&mut self.file, FpMode::Match as u32)
-                    .map_err(|_e| format!("Error doing {:?}", FpMode::Match))?;
-                let event = wait_event_async(
+            let context = self.user_context(user);
+            self.check_if_templates_got_cleared(context)?;
+            // The FPMCU sends InterfaceReady after it reboots, which clears the seed and the templates,
+            // so they are set and uploaded again before matching again
+            let (data, index) = 'upload: loop {
+                self.ensure_seed_is_set().await?;
+                self.load_templates(context, templates, &ids).await?;
+                // The FPMCU also tells us when it notices the finger, before it is done matching it
+                fp_mode(
                     &mut self.file,
-                    [EcMkbpEventType::Fingerprint, EcMkbpEventType::HostEvent],
+                    FpMode::Match as u32 | FpMode::FingerDown as u32,
                 )
-                .await?;
-                match event {
-                    EcMkbpEvent::Fingerprint(fingerprint_event) => {
-                        break fingerprint_event;
-                    }
-                    EcMkbpEvent::HostEvent(host_event) => {
-                        match host_event.rust().map_err(|unexpected_host_event| {
-                            format!("Unexpected host event: {unexpected_host_event}")
-                        })? {
-                            HostEventCode::InterfaceReady => {}
-                            event => unreachable!("Unexpected host event: {event:?}"),
+                .map_err(ec_command_error("fp_mode Match"))?;
+                let fp_event = loop {
+                    let event = match self
+                        .cancel_signal
+                        .or_cancelled(wait_event_async(
+                            &mut self.file,
+                            [EcMkbpEventType::Fingerprint, EcMkbpEventType::HostEvent],
+                        ))
+                        .await
+                    {
+                        Ok(event) => event?,
+                        Err(cancelled) => {
+                            // Stop waiting for a finger. The loaded templates stay loaded.
+                            fp_mode(&mut self.file, FpMode::Reset as u32)
+                                .map_err(ec_command_error("fp_mode Reset"))?;
+                            return Err(cancelled.into());
                         }
+                    };
+                    match event {
+                        EcMkbpEvent::Fingerprint(fingerprint_event) => {
+                            match fingerprint_event.rust() {
+                                EcMkbpEventFingerprintRust::FingerDown => {
+                                    self.finger_down_signal.notify()
+                                }
+                                fp_event => break fp_event,
+                            }
+                        }
+                        EcMkbpEvent::HostEvent(host_event) => {
+                            match host_event.rust().map_err(|unexpected_host_event| {
+                                DriverError::UnexpectedEvent(format!(
+                                    "Unexpected host event: {unexpected_host_event}"
+                                ))
+                            })? {
+                                HostEventCode::InterfaceReady => continue 'upload,
+                                event => {
+                                    return Err(DriverError::UnexpectedEvent(format!(
+                                        "Unexpected host event: {event:?}"
+                                    )))
+                                }
+                            }
+                        }
+                        event => {
+                            return Err(DriverError::UnexpectedEvent(format!(
+                                "Unknown event: {event:?}"
+                            )))
+                        }
+                    }
+                };
+                let data = match fp_event {
+                    EcMkbpEventFingerprintRust::Match(
+                        EcMkbpEventFingerprintMatchResult::Match(data),
+                    ) => data,
+                    EcMkbpEventFingerprintRust::Match(
+                        EcMkbpEventFingerprintMatchResult::NoMatch(result),
+                    ) => {
+                        return Ok(MatchOutput::NoMatch(match result {
+                            Ok(_) => None,
+                            Err(error) => Some(match error {
+                                EcMkbpEventFingerprintNoMatchError::LowQuality => {
+                                    NoMatchError::LowQuality
+                                }
+                                _ => NoMatchError::Other,
+                            }),
+                        }))
+                    }
+                    fp_event => {
+                        return Err(DriverError::UnexpectedEvent(format!(
+                            "Unexpected fp event: {fp_event:?}"
+                        )))
                     }
-                    event => unreachable!("Unknown event: {event:?}"),
                 };
+                let matched_id = self
+                    .slots
+                    .get(data.index)
+                    .ok_or_else(|| {
+                        DriverError::TemplateDesync(format!(
+                            "Matched template {} which is not loaded",
+                            data.index
+                        ))
+                    })?
+                    .id;
+                // The order of templates in the sensor may be different from the order of given templates.
+                // We need to return the index based on the given templates.
+                if let Some(index) = ids.iter().position(|&id| id == matched_id) {
+                    break (data, index);
+                }
+                // A template that is still loaded from before, but wasn't given this time.
+                // It's not a match for the given templates, so keep matching.
             };
-            match fingerprint_event.rust() {
-                EcMkbpEventFingerprintRust::Match(data) => {
-                    Ok(match data {
-                        EcMkbpEventFingerprintMatchResult::Match(data) => {
-                            MatchOutput::Match(MatchedOutput {
-                                index: {
-                                    // The order of templates in the sensor may be different from the order of given templates.
-                                    // We need to return the index based on the given templates.
-                                    let matched_hash = self.loaded_templates_hashes[data.index];
-                                    let input_hash_index = hashes
-                                        .iter()
-                                        .position(|&hash| hash == matched_hash)
-                                        // We unwrap because it's impossible for there to be a match for a template that was not inputted
-                                        .unwrap();
-                                    input_hash_index
-                                },
-                                updated_template: match data.update {
-                                    Some(Ok(_)) => {
-                                        let template = fp_download_template(
-                                            &mut self.file,
-                                            &self.fp_info,
-                                            &self.protocol_info,
-                                            data.index,
-                                        );
-                                        let template: Vec<u8> = template.into();
-                                        self.loaded_templates_hashes[data.index] = {
-                                            let mut hasher = DefaultHasher::default();
-                                            template.hash(&mut hasher);
-                                            hasher.finish()
-                                        };
-                                        Some(template)
-                                    }
-                                    _ => None,
-                                },
-                            })
-                        }
-                        EcMkbpEventFingerprintMatchResult::NoMatch(result) => {
-                            MatchOutput::NoMatch(match result {
-                                Ok(_) => None,
-                                Err(error) => Some(match error {
-                                    EcMkbpEventFingerprintNoMatchError::LowQuality => {
-                                        NoMatchError::LowQuality
-                                    }
-                                    _ => NoMatchError::Other,
-                                }),
-                            })
-                        }
-                    })
+            self.slots[data.index].last_used = self.next_use();
+            Ok(MatchOutput::Match(MatchedOutput {
+                index,
+                id: ids[index],
+                updated_template: match data.update {
+                    Some(Ok(_)) => {
+                        let template = fp_download_template(
+                            &mut self.file,
+                            &self.fp_info,
+                            &self.protocol_info,
+                            data.index,
+                        );
+                        let template: Vec<u8> = template.into();
+                        let slot = &mut self.slots[data.index];
+                        slot.id = TemplateId::new(&template);
+                        slot.template.clone_from(&template);
+                        Some(template)
+                    }
+                    _ => None,
+                },
+            }))
+        })
+    }
+
+    fn wait_finger_down(&mut self) -> BoxFuture<'_, Result<(), DriverError>> {
+        Box::pin(self.wait_finger(true))
+    }
+
+    fn wait_finger_up(&mut self) -> BoxFuture<'_, Result<(), DriverError>> {
+        Box::pin(self.wait_finger(false))
+    }
+
+    fn capture_image(
+        &mut self,
+        capture_type: CaptureType,
+    ) -> BoxFuture<'_, Result<Image, DriverError>> {
+        Box::pin(async move {
+            self.cancel_signal.clear();
+            self.capture(capture_type.into()).await
+        })
+    }
+
+    fn suspend(&mut self) -> Result<(), DriverError> {
+        // Stop waiting for a finger, so that the FPMCU isn't in the middle of something if it keeps running
+        fp_mode(&mut self.file, FpMode::Reset as u32).map_err(ec_command_error("fp_mode Reset"))?;
+        Ok(())
+    }
+
+    fn resume(&mut self) -> BoxFuture<'_, Result<(), DriverError>> {
+        Box::pin(async move {
+            let loaded = self.context.map(|context| (context, self.slots.clone()));
+            // The FPMCU usually reboots during suspend, which clears the seed, context and templates
+            self.ensure_seed_is_set().await?;
+            if let Some((context, slots)) = loaded {
+                self.check_if_templates_got_cleared(context)?;
+                if self.slots.is_empty() && !slots.is_empty() {
+                    // Upload the same slots again, so that the next match doesn't need to upload anything
+                    self.reset(context)?;
+                    // Like in load_templates, uploading right after resetting can fail
+                    sleep(Duration::from_millis(10)).await;
+                    for slot in slots {
+                        self.upload_slot(slot)?;
+                    }
                 }
-                fp_event => Err(format!("Unexpected fp event: {fp_event:?}").into()),
             }
+            Ok(())
+        })
+    }
+
+    fn device_info(&mut self) -> Result<DeviceInfo, DriverError> {
+        // Read it again, since the loaded templates change
+        let info = fp_info(&mut self.file).map_err(ec_command_error("fp_info"))?;
+        let encryption_status = fp_get_encryption_status(&mut self.file)
+            .map_err(ec_command_error("fp_get_encryption_status"))?;
+        Ok(DeviceInfo {
+            vendor_id: info.vendor_id,
+            product_id: info.product_id,
+            model_id: info.model_id,
+            version: info.version,
+            width: info.width.into(),
+            height: info.height.into(),
+            bits_per_pixel: info.bpp as u8,
+            frame_size: info.frame_size,
+            template_size: info.template_size,
+            template_version: info.template_version,
+            max_templates: info.template_max.into(),
+            loaded_templates: info.template_valid.into(),
+            dirty_templates: info.template_dirty,
+            protocol_versions: self.protocol_info.protocol_versions,
+            max_request_size: self.protocol_info.max_request_packet_size.into(),
+            max_response_size: self.protocol_info.max_response_packet_size.into(),
+            seed_set: encryption_status.status & (FpEncryptionStatus::SeedSet as u32) != 0,
         })
     }
+
+    fn self_test(&mut self) -> BoxFuture<'_, Result<SelfTestReport, DriverError>> {
+        Box::pin(async move {
+            self.cancel_signal.clear();
+            let mut checks = Vec::new();
+
+            checks.push(match get_protocol_info(&mut self.file) {
+                Ok(protocol_info) => check(
+                    "Protocol info",
+                    true,
+                    format!(
+                        "Protocol versions {:#x}, max request {} bytes, max response {} bytes",
+                        protocol_info.protocol_versions,
+                        protocol_info.max_request_packet_size,
+                        protocol_info.max_response_packet_size
+                    ),
+                ),
+                Err(e) => check("Protocol info", false, format!("{e:?}")),
+            });
+
+            let info = fp_info(&mut self.file).map_err(ec_command_error("fp_info"))?;
+            checks.push(check(
+                "Firmware",
+                true,
+                format!(
+                    "Vendor {:08x}, product {:08x}, model {:08x}, version {}, {}x{} pixels, {} bits per pixel",
+                    info.vendor_id,
+                    info.product_id,
+                    info.model_id,
+                    info.version,
+                    info.width,
+                    info.height,
+                    info.bpp
+                ),
+            ));
+            let errors = FP_ERRORS
+                .iter()
+                .filter(|(flag, _)| info.errors & flag != 0)
+                .map(|(_, error)| *error)
+                .collect::<Vec<_>>();
+            checks.push(check(
+                "Sensor errors",
+                errors.is_empty(),
+                match errors.is_empty() {
+                    true => "None".into(),
+                    false => errors.join(", "),
+                },
+            ));
+            checks.push(match info.errors & FP_ERROR_DEAD_PIXELS_MASK {
+                FP_ERROR_DEAD_PIXELS_UNKNOWN => {
+                    check("Dead pixels", true, "Not counted by the sensor".into())
+                }
+                dead_pixels => check(
+                    "Dead pixels",
+                    dead_pixels as usize <= MAX_BAD_PIXELS,
+                    format!("{dead_pixels} dead pixels"),
+                ),
+            });
+
+            checks.push(match fp_get_encryption_status(&mut self.file) {
+                Ok(status) => {
+                    let seed_set = status.status & (FpEncryptionStatus::SeedSet as u32) != 0;
+                    check(
+                        "Encryption",
+                        seed_set,
+                        match seed_set {
+                            true => "The seed is set".into(),
+                            false => {
+                                "The seed is not set, so templates can't be enrolled or matched"
+                                    .into()
+                            }
+                        },
+                    )
+                }
+                Err(e) => check("Encryption", false, format!("{e:?}")),
+            });
+
+            let pattern0 = self.capture(FpCaptureType::Pattern0).await?;
+            let pattern1 = self.capture(FpCaptureType::Pattern1).await?;
+            let stuck_pixels = count_stuck_pixels(&pattern0, &pattern1);
+            checks.push(check(
+                "Checkerboard test",
+                stuck_pixels <= MAX_BAD_PIXELS,
+                format!("{stuck_pixels} pixels didn't follow the checkerboard pattern"),
+            ));
+
+            let reset = self.capture(FpCaptureType::ResetTest).await?;
+            let reset_outliers = count_reset_outliers(&reset);
+            checks.push(check(
+                "Reset test",
+                reset_outliers <= MAX_BAD_PIXELS,
+                format!("{reset_outliers} pixels were too far from the median after resetting"),
+            ));
+
+            Ok(SelfTestReport {
+                sensor: self.sensor_id(),
+                checks,
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(n: u8) -> TemplateId {
+        TemplateId([n; 32])
+    }
+
+    fn slot(n: u8, last_used: u64) -> Slot {
+        Slot {
+            id: id(n),
+            template: vec![n],
+            last_used,
+        }
+    }
+
+    #[test]
+    fn loaded_templates_are_not_uploaded_again() {
+        let slots = [slot(1, 1), slot(2, 2)];
+        assert_eq!(
+            plan_load(&slots, true, &[id(2), id(1)], 5).unwrap(),
+            LoadPlan::Loaded
+        );
+    }
+
+    #[test]
+    fn missing_templates_go_in_free_slots() {
+        let slots = [slot(1, 1)];
+        assert_eq!(
+            plan_load(&slots, true, &[id(1), id(2)], 5).unwrap(),
+            LoadPlan::Append { missing: vec![1] }
+        );
+    }
+
+    #[test]
+    fn duplicate_templates_take_one_slot() {
+        assert_eq!(
+            plan_load(&[], true, &[id(3), id(3)], 5).unwrap(),
+            LoadPlan::Append { missing: vec![0] }
+        );
+    }
+
+    #[test]
+    fn least_recently_used_slot_is_evicted() {
+        let slots = [slot(1, 1), slot(2, 5), slot(3, 3)];
+        assert_eq!(
+            plan_load(&slots, true, &[id(4)], 3).unwrap(),
+            LoadPlan::Reset {
+                keep: vec![1, 2],
+                missing: vec![0]
+            }
+        );
+    }
+
+    #[test]
+    fn templates_being_loaded_are_kept() {
+        let slots = [slot(1, 1), slot(2, 5), slot(3, 3)];
+        assert_eq!(
+            plan_load(&slots, true, &[id(1), id(4)], 3).unwrap(),
+            LoadPlan::Reset {
+                keep: vec![0, 1],
+                missing: vec![1]
+            }
+        );
+    }
+
+    #[test]
+    fn other_users_slots_are_not_kept() {
+        let slots = [slot(1, 1)];
+        assert_eq!(
+            plan_load(&slots, false, &[id(1)], 5).unwrap(),
+            LoadPlan::Reset {
+                keep: vec![],
+                missing: vec![0]
+            }
+        );
+    }
+
+    #[test]
+    fn every_given_template_is_kept_even_if_it_was_never_matched() {
+        let slots = [slot(1, 0), slot(2, 0), slot(3, 7), slot(4, 9)];
+        assert_eq!(
+            plan_load(&slots, true, &[id(1), id(2), id(5)], 4).unwrap(),
+            LoadPlan::Reset {
+                keep: vec![0, 1, 3],
+                missing: vec![2]
+            }
+        );
+    }
+
+    #[test]
+    fn only_given_templates_are_kept_when_they_fill_every_slot() {
+        let slots = [slot(1, 0), slot(2, 5)];
+        assert_eq!(
+            plan_load(&slots, true, &[id(1), id(3), id(4)], 3).unwrap(),
+            LoadPlan::Reset {
+                keep: vec![0],
+                missing: vec![1, 2]
+            }
+        );
+    }
+
+    #[test]
+    fn too_many_templates_are_rejected() {
+        assert!(matches!(
+            plan_load(&[], true, &[id(1), id(2), id(3)], 2),
+            Err(DriverError::TooManyTemplates { count: 3, max: 2 })
+        ));
+        // Duplicates don't count
+        assert_eq!(
+            plan_load(&[], true, &[id(1), id(2), id(1)], 2).unwrap(),
+            LoadPlan::Append {
+                missing: vec![0, 1]
+            }
+        );
+    }
+
+    #[test]
+    fn firmware_version() {
+        let version_file = "RO version:    nocturne_fp_v2.2.64-58cf5974e\nRW version:    nocturne_fp_v2.2.144-7a08e07eb\nFirmware copy: RW\nBuild info:    nocturne_fp_v2.2.144-7a08e07eb boringssl:v0.0.3 2019-12-06 00:37:28 @chromeos-ci\n";
+        assert_eq!(
+            parse_firmware_version(version_file),
+            "RO nocturne_fp_v2.2.64-58cf5974e, RW nocturne_fp_v2.2.144-7a08e07eb"
+        );
+        assert_eq!(parse_firmware_version(""), "");
+    }
 }
@@ -1,12 +1,20 @@
 use crate::drivers::cros_fp::CrosFp;
+#[cfg(feature = "simulated")]
+use crate::drivers::simulated::Simulated;
 use crate::fingerprint_driver::FingerprintDriver;
 
 mod cros_fp;
+#[cfg(feature = "simulated")]
+pub mod simulated;
 
 trait GetFingerprintDriver {
     fn get_driver() -> FingerprintDriver;
 }
 
 pub fn get_drivers() -> Vec<FingerprintDriver> {
-    vec![CrosFp::get_driver()]
+    vec![
+        CrosFp::get_driver(),
+        #[cfg(feature = "simulated")]
+        Simulated::get_driver(),
+    ]
 }
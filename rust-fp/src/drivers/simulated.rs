@@ -0,0 +1,505 @@
+use std::{
+    collections::VecDeque,
+    env,
+    io::{self, ErrorKind},
+};
+
+use async_std::{
+    channel::{unbounded, Receiver, Sender},
+    fs::read_to_string,
+};
+use futures::future::{pending, BoxFuture};
+
+use crate::drivers::GetFingerprintDriver;
+use crate::fingerprint_driver::{
+    CancelSignal, Cancelled, Canceller, Capabilities, DriverError, EnrollStepError,
+    EnrollStepOutput, EnrollStepResult, FingerDownListener, FingerDownSignal, FingerprintDriver,
+    MatchOutput, MatchedOutput, NoMatchError, OpenedFingerprintDriver, ScanType, SelfTestCheck,
+    SelfTestReport, SensorId, TemplateId,
+};
+
+/// Path to a script file with finger touches. The simulated driver is only compatible if this is set.
+pub const SCRIPT_ENV_VAR: &str = "RUST_FP_SIMULATED";
+const DEFAULT_ENROLL_STEPS: u8 = 5;
+const MAX_TEMPLATES: usize = 5;
+
+/// Something that happens on the simulated sensor
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SimulatedTouch {
+    /// A good quality touch by a finger. Fingers with the same id produce matching templates.
+    Finger(u32),
+    /// A touch that was too bad to use
+    LowQuality,
+}
+
+/// A list of touches and settings, usually parsed from a script file.
+///
+/// Script files have one command per line. Empty lines and lines starting with `#` are ignored.
+/// ```text
+/// enroll-steps 3
+/// finger 1
+/// low-quality
+/// finger 1
+/// finger 1
+/// # Matches the finger enrolled above
+/// finger 1
+/// # Doesn't match
+/// finger 2
+/// ```
+#[derive(Debug, Clone)]
+pub struct SimulatedScript {
+    pub enroll_steps: u8,
+    pub touches: Vec<SimulatedTouch>,
+}
+
+impl Default for SimulatedScript {
+    fn default() -> Self {
+        Self {
+            enroll_steps: DEFAULT_ENROLL_STEPS,
+            touches: Default::default(),
+        }
+    }
+}
+
+impl SimulatedScript {
+    pub fn parse(script: &str) -> io::Result<Self> {
+        let mut parsed = Self::default();
+        for (line_index, line) in script.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = || {
+                io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "Invalid line {} in simulated script: {line:?}",
+                        line_index + 1
+                    ),
+                )
+            };
+            let mut words = line.split_whitespace();
+            match (words.next(), words.next(), words.next()) {
+                (Some("enroll-steps"), Some(steps), None) => {
+                    parsed.enroll_steps = steps
+                        .parse()
+                        .ok()
+                        .filter(|&steps| steps > 0)
+                        .ok_or_else(invalid)?;
+                }
+                (Some("finger"), Some(finger), None) => {
+                    parsed.touches.push(SimulatedTouch::Finger(
+                        finger.parse().map_err(|_e| invalid())?,
+                    ));
+                }
+                (Some("low-quality"), None, None) => {
+                    parsed.touches.push(SimulatedTouch::LowQuality);
+                }
+                _ => return Err(invalid()),
+            }
+        }
+        Ok(parsed)
+    }
+}
+
+/// Lets code in the same process touch the simulated sensor
+#[derive(Debug, Clone)]
+pub struct SimulatedControl {
+    sender: Sender<SimulatedTouch>,
+}
+
+impl SimulatedControl {
+    pub fn touch(&self, touch: SimulatedTouch) {
+        // The channel is unbounded, so this only fails if the driver was dropped
+        let _ = self.sender.try_send(touch);
+    }
+}
+
+pub struct Simulated;
+
+impl GetFingerprintDriver for Simulated {
+    fn get_driver() -> FingerprintDriver {
+        FingerprintDriver {
+            name: "Simulated",
+            device_nodes: &[],
+            is_compatible: Box::new(|| {
+                Box::pin(async { Ok(env::var_os(SCRIPT_ENV_VAR).is_some()) })
+            }),
+            // The simulated sensor doesn't encrypt templates, so it doesn't need a seed
+            open_and_init: Box::new(|_key_provider| {
+                Box::pin(async {
+                    let script = match env::var_os(SCRIPT_ENV_VAR) {
+                        Some(path) if !path.is_empty() => {
+                            SimulatedScript::parse(&read_to_string(path).await?)?
+                        }
+                        _ => Default::default(),
+                    };
+                    let (opened_simulated, _control) = OpenedSimulated::new(script);
+                    Ok(Box::new(opened_simulated) as Box<dyn OpenedFingerprintDriver>)
+                })
+            }),
+        }
+    }
+}
+
+pub struct OpenedSimulated {
+    touches: VecDeque<SimulatedTouch>,
+    receiver: Receiver<SimulatedTouch>,
+    enroll_steps: u8,
+    enrolled_steps: u8,
+    enrolling_finger: Option<u32>,
+    cancel_signal: CancelSignal,
+    finger_down_signal: FingerDownSignal,
+}
+
+impl OpenedSimulated {
+    /// Creates a simulated sensor which first plays back the script, and then waits for touches from the control
+    pub fn new(script: SimulatedScript) -> (Self, SimulatedControl) {
+        let (sender, receiver) = unbounded();
+        (
+            Self {
+                touches: script.touches.into(),
+                receiver,
+                enroll_steps: script.enroll_steps,
+                enrolled_steps: 0,
+                enrolling_finger: None,
+                cancel_signal: Default::default(),
+                finger_down_signal: Default::default(),
+            },
+            SimulatedControl { sender },
+        )
+    }
+
+    /// Waits until the sensor gets touched, just like a real sensor
+    async fn wait_touch(&mut self) -> Result<SimulatedTouch, Cancelled> {
+        self.cancel_signal.clear();
+        match self.touches.pop_front() {
+            Some(touch) => Ok(touch),
+            None => {
+                let receiver = &self.receiver;
+                self.cancel_signal
+                    .or_cancelled(async {
+                        match receiver.recv().await {
+                            Ok(touch) => touch,
+                            // Without a control, nothing touches the sensor anymore
+                            Err(_closed) => pending().await,
+                        }
+                    })
+                    .await
+            }
+        }
+    }
+
+    /// Like templates from a real sensor, templates only match for the user that they were enrolled for
+    fn template(user: u32, finger: u32) -> Vec<u8> {
+        format!("rust-fp simulated user {user} finger {finger}").into_bytes()
+    }
+}
+
+impl OpenedFingerprintDriver for OpenedSimulated {
+    fn canceller(&self) -> Canceller {
+        self.cancel_signal.canceller()
+    }
+
+    fn finger_down_listener(&self) -> Option<FingerDownListener> {
+        Some(self.finger_down_signal.listener())
+    }
+
+    fn start_or_continue_enroll(&mut self, user: u32) -> BoxFuture<'_, EnrollStepResult> {
+        Box::pin(async move {
+            let touch = self.wait_touch().await.map_err(|_cancelled| {
+                // Cancelling ends the enroll session
+                self.enrolled_steps = 0;
+                self.enrolling_finger = None;
+                EnrollStepError::Cancelled
+            })?;
+            self.finger_down_signal.notify();
+            let finger = match touch {
+                SimulatedTouch::Finger(finger) => finger,
+                SimulatedTouch::LowQuality => return Err(EnrollStepError::LowQuality),
+            };
+            // Like a real sensor, the template comes from the finger that started the enroll session
+            let finger = *self.enrolling_finger.get_or_insert(finger);
+            self.enrolled_steps += 1;
+            Ok(match self.enrolled_steps >= self.enroll_steps {
+                true => {
+                    self.enrolled_steps = 0;
+                    self.enrolling_finger = None;
+                    EnrollStepOutput::Complete(Self::template(user, finger))
+                }
+                false => EnrollStepOutput::InProgress(
+                    (self.enrolled_steps as u32 * 100 / self.enroll_steps as u32) as u8,
+                ),
+            })
+        })
+    }
+
+    fn abort_enroll(&mut self) -> Result<(), DriverError> {
+        self.enrolled_steps = 0;
+        self.enrolling_finger = None;
+        Ok(())
+    }
+
+    fn get_max_templates(&mut self) -> Result<usize, DriverError> {
+        Ok(MAX_TEMPLATES)
+    }
+
+    fn sensor_id(&self) -> SensorId {
+        SensorId {
+            id: "simulated".into(),
+            hardware_version: "1".into(),
+            firmware_version: "1".into(),
+        }
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        // Like a Chromebook sensor, templates are compared by the "sensor"
+        Capabilities {
+            on_chip_matching: true,
+            max_templates: MAX_TEMPLATES,
+            template_size: None,
+            image_capture: false,
+            finger_detect: true,
+            template_update_on_match: false,
+            scan_type: ScanType::Press,
+            enroll_stages: self.enroll_steps as u32,
+        }
+    }
+
+    fn take_reset_detected(&mut self) -> bool {
+        // The simulated sensor never resets
+        false
+    }
+
+    fn match_templates<'a>(
+        &'a mut self,
+        user: u32,
+        templates: &'a [Vec<u8>],
+    ) -> BoxFuture<'a, Result<MatchOutput, DriverError>> {
+        Box::pin(async move {
+            let touch = self.wait_touch().await?;
+            self.finger_down_signal.notify();
+            Ok(match touch {
+                SimulatedTouch::Finger(finger) => {
+                    let template = Self::template(user, finger);
+                    match templates.iter().position(|t| t == &template) {
+                        Some(index) => MatchOutput::Match(MatchedOutput {
+                            index,
+                            id: TemplateId::new(&template),
+                            updated_template: None,
+                        }),
+                        None => MatchOutput::NoMatch(None),
+                    }
+                }
+                SimulatedTouch::LowQuality => MatchOutput::NoMatch(Some(NoMatchError::LowQuality)),
+            })
+        })
+    }
+
+    fn wait_finger_down(&mut self) -> BoxFuture<'_, Result<(), DriverError>> {
+        Box::pin(async move {
+            let touch = self.wait_touch().await?;
+            // The finger stays on the sensor for the next enroll step or match
+            self.touches.push_front(touch);
+            Ok(())
+        })
+    }
+
+    fn wait_finger_up(&mut self) -> BoxFuture<'_, Result<(), DriverError>> {
+        // Simulated touches are lifted right away
+        Box::pin(async { Ok(()) })
+    }
+
+    fn self_test(&mut self) -> BoxFuture<'_, Result<SelfTestReport, DriverError>> {
+        Box::pin(async move {
+            // There's no hardware to test, but this lets frontends try out showing a report
+            Ok(SelfTestReport {
+                sensor: self.sensor_id(),
+                checks: vec![SelfTestCheck {
+                    name: "Script".into(),
+                    passed: true,
+                    details: format!("{} touches left in the script", self.touches.len()),
+                }],
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use async_std::future::timeout;
+    use async_std::task::{block_on, sleep};
+
+    use super::*;
+
+    #[test]
+    fn parse_script() {
+        let script = SimulatedScript::parse(
+            "enroll-steps 3\n\n# A comment\nfinger 1\n  low-quality  \nfinger 2\n",
+        )
+        .unwrap();
+        assert_eq!(script.enroll_steps, 3);
+        assert_eq!(
+            script.touches,
+            vec![
+                SimulatedTouch::Finger(1),
+                SimulatedTouch::LowQuality,
+                SimulatedTouch::Finger(2)
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_script_defaults() {
+        let script = SimulatedScript::parse("").unwrap();
+        assert_eq!(script.enroll_steps, DEFAULT_ENROLL_STEPS);
+        assert!(script.touches.is_empty());
+    }
+
+    #[test]
+    fn parse_invalid_script() {
+        for script in [
+            "finger",
+            "finger one",
+            "finger 1 2",
+            "enroll-steps 0",
+            "low-quality 1",
+            "swipe",
+        ] {
+            let error = SimulatedScript::parse(script).unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::InvalidData, "{script:?}");
+        }
+    }
+
+    #[test]
+    fn enroll_then_match() {
+        block_on(async {
+            let script = SimulatedScript::parse(
+                "enroll-steps 2\nfinger 1\nlow-quality\nfinger 2\nfinger 1\nfinger 2\nlow-quality",
+            )
+            .unwrap();
+            let (mut driver, _control) = OpenedSimulated::new(script);
+            assert!(matches!(
+                driver.start_or_continue_enroll(1000).await,
+                Ok(EnrollStepOutput::InProgress(50))
+            ));
+            assert!(matches!(
+                driver.start_or_continue_enroll(1000).await,
+                Err(EnrollStepError::LowQuality)
+            ));
+            // The template is from the finger that started the session
+            let template = match driver.start_or_continue_enroll(1000).await {
+                Ok(EnrollStepOutput::Complete(template)) => template,
+                output => panic!("Enroll didn't complete: {output:?}"),
+            };
+            let templates = [b"other".to_vec(), template.clone()];
+
+            match driver.match_templates(1000, &templates).await.unwrap() {
+                MatchOutput::Match(matched) => {
+                    assert_eq!(matched.index, 1);
+                    assert_eq!(matched.id, TemplateId::new(&template));
+                }
+                output => panic!("Didn't match: {output:?}"),
+            }
+            assert!(matches!(
+                driver.match_templates(1000, &templates).await.unwrap(),
+                MatchOutput::NoMatch(None)
+            ));
+            assert!(matches!(
+                driver.match_templates(1000, &templates).await.unwrap(),
+                MatchOutput::NoMatch(Some(NoMatchError::LowQuality))
+            ));
+        });
+    }
+
+    #[test]
+    fn templates_only_match_for_their_user() {
+        block_on(async {
+            let script = SimulatedScript::parse("enroll-steps 1\nfinger 1\nfinger 1").unwrap();
+            let (mut driver, _control) = OpenedSimulated::new(script);
+            let Ok(EnrollStepOutput::Complete(template)) =
+                driver.start_or_continue_enroll(1000).await
+            else {
+                panic!("Enroll didn't complete");
+            };
+            assert!(matches!(
+                driver.match_templates(1001, &[template]).await.unwrap(),
+                MatchOutput::NoMatch(None)
+            ));
+        });
+    }
+
+    #[test]
+    fn control_touches_after_script() {
+        block_on(async {
+            let script = SimulatedScript::parse("enroll-steps 1\nfinger 3").unwrap();
+            let (mut driver, control) = OpenedSimulated::new(script);
+            let Ok(EnrollStepOutput::Complete(template)) =
+                driver.start_or_continue_enroll(1000).await
+            else {
+                panic!("Enroll didn't complete");
+            };
+            control.touch(SimulatedTouch::Finger(3));
+            assert!(matches!(
+                driver.match_templates(1000, &[template]).await.unwrap(),
+                MatchOutput::Match(MatchedOutput { index: 0, .. })
+            ));
+        });
+    }
+
+    #[test]
+    fn finger_down_is_told_before_the_output() {
+        block_on(async {
+            let script = SimulatedScript::parse("enroll-steps 2\nfinger 1").unwrap();
+            let (mut driver, control) = OpenedSimulated::new(script);
+            let listener = driver.finger_down_listener().unwrap();
+            assert!(matches!(
+                driver.start_or_continue_enroll(1000).await,
+                Ok(EnrollStepOutput::InProgress(50))
+            ));
+            assert!(timeout(Duration::from_millis(50), listener.wait())
+                .await
+                .is_ok());
+            // Nothing touched the sensor yet
+            assert!(timeout(Duration::from_millis(50), listener.wait())
+                .await
+                .is_err());
+            let (output, ()) = futures::join!(driver.match_templates(1000, &[]), async {
+                listener.clear();
+                control.touch(SimulatedTouch::LowQuality);
+                listener.wait().await;
+            });
+            assert!(matches!(
+                output.unwrap(),
+                MatchOutput::NoMatch(Some(NoMatchError::LowQuality))
+            ));
+        });
+    }
+
+    #[test]
+    fn waits_forever_after_script_without_control() {
+        block_on(async {
+            let (mut driver, control) = OpenedSimulated::new(Default::default());
+            drop(control);
+            let canceller = driver.canceller();
+            assert!(
+                timeout(Duration::from_millis(50), driver.match_templates(1000, &[]))
+                    .await
+                    .is_err()
+            );
+            assert!(
+                timeout(Duration::from_millis(50), driver.wait_finger_down())
+                    .await
+                    .is_err()
+            );
+            // It can still be cancelled
+            let (output, ()) = futures::join!(driver.match_templates(1000, &[]), async {
+                sleep(Duration::from_millis(10)).await;
+                canceller.cancel();
+            });
+            assert!(matches!(output, Err(DriverError::Cancelled)));
+        });
+    }
+}
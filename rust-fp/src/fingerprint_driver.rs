@@ -1,15 +1,121 @@
-use futures::future::BoxFuture;
+use async_std::channel::{bounded, Receiver, Sender};
+use futures::future::{pending, select, BoxFuture, Either};
 use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::future::Future;
 use std::io;
+use std::pin::pin;
+use std::sync::Arc;
 
-type OpenAndInit =
-    Box<dyn Fn() -> BoxFuture<'static, io::Result<Box<dyn OpenedFingerprintDriver>>>>;
+use sha2::{Digest, Sha256};
+
+use crate::key_provider::KeyProvider;
+
+type OpenAndInit = Box<
+    dyn Fn(
+        Arc<dyn KeyProvider>,
+    ) -> BoxFuture<'static, Result<Box<dyn OpenedFingerprintDriver>, DriverError>>,
+>;
 
 pub struct FingerprintDriver {
     /// Returns `true` if this device has a  fingerprint sensor compatible with this driver
     pub is_compatible: Box<dyn Fn() -> BoxFuture<'static, io::Result<bool>>>,
     pub name: &'static str,
     pub open_and_init: OpenAndInit,
+    /// Device files that the sensor is used through. If one of them is created or deleted,
+    /// the sensor was probably plugged in or unplugged, and the driver should be checked again.
+    pub device_nodes: &'static [&'static str],
+}
+
+/// Something went wrong talking to the sensor. Errors are stored as text so that they can be sent to clients.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug)]
+pub enum DriverError {
+    /// Error reading or writing to the sensor
+    Io(String),
+    /// The sensor responded to a command with an error
+    EcCommand {
+        command: String,
+        error: String,
+    },
+    /// The sensor was in a mode that the driver doesn't know how to get out of
+    UnexpectedMode(u32),
+    /// The templates on the sensor are not the templates that the driver thinks are on the sensor
+    TemplateDesync(String),
+    /// The sensor sent an event that the driver wasn't waiting for
+    UnexpectedEvent(String),
+    Cancelled,
+    /// The sensor was unplugged, or it couldn't be opened
+    Unavailable,
+    /// The driver can't do this with the sensor
+    Unsupported,
+    /// The sensor didn't respond in time
+    Timeout,
+    /// Something else is using the sensor in a way that can't be interrupted, like an enroll session
+    Busy,
+    /// More templates were given than the sensor can load at once
+    TooManyTemplates {
+        count: usize,
+        max: usize,
+    },
+}
+
+impl Error for DriverError {}
+
+impl Display for DriverError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(e) => {
+                write!(f, "IO error: {}", e)
+            }
+            Self::EcCommand { command, error } => {
+                write!(f, "Error doing {}: {}", command, error)
+            }
+            Self::UnexpectedMode(mode) => {
+                write!(f, "Unexpected fp mode: {:#x}", mode)
+            }
+            Self::TemplateDesync(e) => {
+                write!(f, "Templates on the sensor are out of sync: {}", e)
+            }
+            Self::UnexpectedEvent(event) => {
+                write!(f, "Unexpected event: {}", event)
+            }
+            Self::Cancelled => {
+                write!(f, "{}", Cancelled)
+            }
+            Self::Unavailable => {
+                write!(f, "Fingerprint sensor is unavailable")
+            }
+            Self::Unsupported => {
+                write!(f, "Not supported by the fingerprint sensor")
+            }
+            Self::Timeout => {
+                write!(f, "Fingerprint sensor didn't respond in time")
+            }
+            Self::Busy => {
+                write!(f, "Fingerprint sensor is busy. Wait until it's done.")
+            }
+            Self::TooManyTemplates { count, max } => {
+                write!(
+                    f,
+                    "Can't match {} templates at once, the sensor only fits {}",
+                    count, max
+                )
+            }
+        }
+    }
+}
+
+impl From<io::Error> for DriverError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(format!("{e:?}"))
+    }
+}
+
+impl From<Cancelled> for DriverError {
+    fn from(_: Cancelled) -> Self {
+        Self::Cancelled
+    }
 }
 
 #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
@@ -17,6 +123,19 @@ pub struct FingerprintDriver {
 pub enum EnrollStepError {
     GenericError,
     LowQuality,
+    /// The finger wasn't moved since the last touch, so the touch didn't add anything
+    Immobile,
+    Cancelled,
+    Driver(DriverError),
+}
+
+impl From<DriverError> for EnrollStepError {
+    fn from(e: DriverError) -> Self {
+        match e {
+            DriverError::Cancelled => Self::Cancelled,
+            e => Self::Driver(e),
+        }
+    }
 }
 
 #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
@@ -28,10 +147,34 @@ pub enum EnrollStepOutput {
 
 pub type EnrollStepResult = Result<EnrollStepOutput, EnrollStepError>;
 
+/// The SHA-256 digest of a template. It's the same for the same template bytes, no matter which process or version of rust-fp computed it,
+/// so it can be stored and used to refer to a template.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct TemplateId(pub [u8; 32]);
+
+impl TemplateId {
+    pub fn new(template: &[u8]) -> Self {
+        Self(Sha256::digest(template).into())
+    }
+}
+
+impl Display for TemplateId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for byte in self.0 {
+            write!(f, "{byte:02x}")?;
+        }
+        Ok(())
+    }
+}
+
 #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[derive(Debug)]
 pub struct MatchedOutput {
+    /// The index of the first given template with the matched template's id
     pub index: usize,
+    /// The id of the template that matched, from before it was updated
+    pub id: TemplateId,
     /// If the template was updated successfully, the updated template should be outputted
     pub updated_template: Option<Vec<u8>>,
 }
@@ -50,11 +193,320 @@ pub enum MatchOutput {
     NoMatch(Option<NoMatchError>),
 }
 
+/// Identifies a sensor model. Templates usually only work on the sensor that enrolled them.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SensorId {
+    /// Identifies the sensor model. Templates from a sensor with a different id won't match.
+    pub id: String,
+    /// The sensor's hardware revision. Only informational, templates work across revisions.
+    pub hardware_version: String,
+    /// The firmware that the sensor runs. Only informational, and empty if unknown.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub firmware_version: String,
+}
+
+/// How a finger is put on the sensor
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ScanType {
+    /// The finger is placed on the sensor
+    #[default]
+    Press,
+    /// The finger is moved across the sensor
+    Swipe,
+}
+
+/// What a sensor and its driver can do, so that frontends can adapt their UI
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Capabilities {
+    /// Templates are matched by the sensor instead of by the computer
+    pub on_chip_matching: bool,
+    /// The number of templates that can be matched at a time
+    pub max_templates: usize,
+    /// The size of every template in bytes, if they all have the same size
+    pub template_size: Option<usize>,
+    /// The driver can capture images of fingers
+    pub image_capture: bool,
+    /// The driver can wait for a finger to be put on or taken off the sensor without matching it
+    pub finger_detect: bool,
+    /// Matching can give an updated template, which should be saved
+    pub template_update_on_match: bool,
+    pub scan_type: ScanType,
+    /// The number of good touches that are needed to enroll a finger
+    pub enroll_stages: u32,
+}
+
+/// What [`OpenedFingerprintDriver::capture_image`] captures
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CaptureType {
+    /// The finger that is put on the sensor
+    #[default]
+    Simple,
+    /// A checkerboard test pattern, without a finger
+    Pattern,
+    /// The finger, captured the way the sensor's image quality test does
+    QualityTest,
+    /// The sensor right after resetting it, without a finger
+    ResetTest,
+}
+
+/// A raw image from the sensor. Pixels are row by row, and pixels with more than 8 bits are little endian.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Image {
+    pub width: u32,
+    pub height: u32,
+    pub bits_per_pixel: u8,
+    pub pixels: Vec<u8>,
+}
+
+/// Low level information about a sensor, from [`OpenedFingerprintDriver::device_info`]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeviceInfo {
+    pub vendor_id: u32,
+    pub product_id: u32,
+    pub model_id: u32,
+    /// The version of the sensor
+    pub version: u32,
+    pub width: u32,
+    pub height: u32,
+    pub bits_per_pixel: u8,
+    /// The size of a raw image in bytes
+    pub frame_size: u32,
+    pub template_size: u32,
+    pub template_version: u32,
+    pub max_templates: u32,
+    /// The number of templates that are loaded on the sensor
+    pub loaded_templates: u32,
+    /// A bit for every loaded template that was updated by matching and not downloaded yet
+    pub dirty_templates: u32,
+    /// A bit for every version of the host command protocol that the sensor supports
+    pub protocol_versions: u32,
+    pub max_request_size: u32,
+    pub max_response_size: u32,
+    /// If the seed that encrypts templates is set
+    pub seed_set: bool,
+}
+
+/// One thing that [`OpenedFingerprintDriver::self_test`] checked
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SelfTestCheck {
+    pub name: String,
+    pub passed: bool,
+    /// What was found, for someone diagnosing the sensor
+    pub details: String,
+}
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SelfTestReport {
+    pub sensor: SensorId,
+    pub checks: Vec<SelfTestCheck>,
+}
+
+impl SelfTestReport {
+    pub fn passed(&self) -> bool {
+        self.checks.iter().all(|check| check.passed)
+    }
+}
+
+/// The error returned by an operation that was aborted with a [`Canceller`]
+#[derive(Debug)]
+pub struct Cancelled;
+
+impl Error for Cancelled {}
+
+impl Display for Cancelled {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Operation was cancelled")
+    }
+}
+
+/// Cancels the enroll step or match that the driver is currently waiting on.
+/// It can be used while something else is borrowing the driver.
+#[derive(Debug, Clone)]
+pub struct Canceller(Sender<()>);
+
+impl Canceller {
+    pub fn cancel(&self) {
+        // If a cancel is already pending, there's no need to send another one
+        let _ = self.0.try_send(());
+    }
+}
+
+/// Kept by drivers to find out when a [`Canceller`] was used
+#[derive(Debug)]
+pub struct CancelSignal {
+    sender: Sender<()>,
+    receiver: Receiver<()>,
+}
+
+impl Default for CancelSignal {
+    fn default() -> Self {
+        let (sender, receiver) = bounded(1);
+        Self { sender, receiver }
+    }
+}
+
+impl CancelSignal {
+    pub fn canceller(&self) -> Canceller {
+        Canceller(self.sender.clone())
+    }
+
+    /// Forgets about cancels that happened while nothing was running, so they don't cancel the next operation
+    pub fn clear(&self) {
+        while self.receiver.try_recv().is_ok() {}
+    }
+
+    /// Waits for the future unless it gets cancelled first
+    pub async fn or_cancelled<T>(&self, future: impl Future<Output = T>) -> Result<T, Cancelled> {
+        match select(pin!(future), pin!(self.receiver.recv())).await {
+            Either::Left((output, _)) => Ok(output),
+            Either::Right(_) => Err(Cancelled),
+        }
+    }
+}
+
+/// Finds out when the sensor notices a finger during an enroll step or match, before the sensor is done with it.
+/// It can be used while something else is borrowing the driver.
+#[derive(Debug, Clone)]
+pub struct FingerDownListener(Receiver<()>);
+
+impl FingerDownListener {
+    /// Waits until the sensor notices a finger
+    pub async fn wait(&self) {
+        if self.0.recv().await.is_err() {
+            // The driver was dropped, so it won't notice any more fingers
+            pending::<()>().await;
+        }
+    }
+
+    /// Forgets about fingers noticed before the operation that is about to run
+    pub fn clear(&self) {
+        while self.0.try_recv().is_ok() {}
+    }
+}
+
+/// Kept by drivers to tell a [`FingerDownListener`] when the sensor notices a finger
+#[derive(Debug)]
+pub struct FingerDownSignal {
+    sender: Sender<()>,
+    receiver: Receiver<()>,
+}
+
+impl Default for FingerDownSignal {
+    fn default() -> Self {
+        let (sender, receiver) = bounded(1);
+        Self { sender, receiver }
+    }
+}
+
+impl FingerDownSignal {
+    pub fn listener(&self) -> FingerDownListener {
+        FingerDownListener(self.receiver.clone())
+    }
+
+    pub fn notify(&self) {
+        // If the listener wasn't told about the last finger yet, it doesn't need to be told again
+        let _ = self.sender.try_send(());
+    }
+}
+
 pub trait OpenedFingerprintDriver: Sync + Send {
-    fn start_or_continue_enroll(&mut self) -> BoxFuture<EnrollStepResult>;
-    fn get_max_templates(&mut self) -> anyhow::Result<usize>;
+    /// Get a [`Canceller`] which can abort in-flight [`Self::start_or_continue_enroll`], [`Self::match_templates`],
+    /// [`Self::wait_finger_down`] and [`Self::wait_finger_up`] calls
+    fn canceller(&self) -> Canceller;
+    /// Templates are enrolled for a user, and can only be matched for the same user
+    fn start_or_continue_enroll(&mut self, user: u32) -> BoxFuture<'_, EnrollStepResult>;
+    /// Ends the enroll session between enroll steps, so that the next enroll step starts a new one
+    fn abort_enroll(&mut self) -> Result<(), DriverError>;
+    fn get_max_templates(&mut self) -> Result<usize, DriverError>;
+    fn sensor_id(&self) -> SensorId;
+    fn capabilities(&self) -> Capabilities;
+    /// Returns `true` once after the driver notices that the sensor reset itself and lost its loaded templates,
+    /// which can happen during suspend
+    fn take_reset_detected(&mut self) -> bool;
+    /// If templates are encrypted with [`crate::key_provider::LEGACY_SEED`], so that they only work with that seed
+    fn uses_legacy_seed(&self) -> bool {
+        false
+    }
+    /// Get a [`FingerDownListener`] which is told when the sensor notices a finger during
+    /// [`Self::start_or_continue_enroll`] and [`Self::match_templates`]. `None` if the driver can't tell.
+    fn finger_down_listener(&self) -> Option<FingerDownListener> {
+        None
+    }
+    /// Matches against templates that were enrolled for the user.
+    /// Templates with the same [`TemplateId`] are only loaded once, and a match is reported with the index of the first one.
     fn match_templates<'a>(
         &'a mut self,
+        user: u32,
         templates: &'a [Vec<u8>],
-    ) -> BoxFuture<Result<MatchOutput, Box<dyn Error>>>;
+    ) -> BoxFuture<'a, Result<MatchOutput, DriverError>>;
+    /// Waits until a finger is on the sensor, without matching or enrolling it.
+    /// Only works if [`Capabilities::finger_detect`] is `true`. An enroll session in progress is kept going.
+    fn wait_finger_down(&mut self) -> BoxFuture<'_, Result<(), DriverError>>;
+    /// Waits until there is no finger on the sensor.
+    /// Only works if [`Capabilities::finger_detect`] is `true`. An enroll session in progress is kept going.
+    fn wait_finger_up(&mut self) -> BoxFuture<'_, Result<(), DriverError>>;
+    /// Captures a raw image, for diagnosing the sensor. Waits for a finger if the capture type needs one.
+    /// Only works if [`Capabilities::image_capture`] is `true`. An enroll session in progress is ended.
+    fn capture_image(
+        &mut self,
+        _capture_type: CaptureType,
+    ) -> BoxFuture<'_, Result<Image, DriverError>> {
+        Box::pin(async { Err(DriverError::Unsupported) })
+    }
+    /// Gets the sensor ready for the computer sleeping. Nothing else is done with the driver until [`Self::resume`].
+    fn suspend(&mut self) -> Result<(), DriverError> {
+        Ok(())
+    }
+    /// Gets the sensor ready to be used again after the computer wakes up,
+    /// so that the first enroll or match after waking up is as fast as any other
+    fn resume(&mut self) -> BoxFuture<'_, Result<(), DriverError>> {
+        Box::pin(async { Ok(()) })
+    }
+    /// Reads low level information from the sensor, for debugging
+    fn device_info(&mut self) -> Result<DeviceInfo, DriverError> {
+        Err(DriverError::Unsupported)
+    }
+    /// Tests the sensor's hardware and firmware. Failed checks are in the report, and errors mean that testing couldn't finish.
+    /// An enroll session in progress is ended.
+    fn self_test(&mut self) -> BoxFuture<'_, Result<SelfTestReport, DriverError>> {
+        Box::pin(async { Err(DriverError::Unsupported) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Saved ids and the ids shown by `rust-fp list` must never change, so these are fixed SHA-256 test vectors
+    #[test]
+    fn template_id_is_sha256() {
+        assert_eq!(
+            TemplateId::new(b"").to_string(),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            TemplateId::new(b"abc").to_string(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn template_id_only_depends_on_the_template() {
+        assert_eq!(TemplateId::new(&[1, 2, 3]), TemplateId::new(&[1, 2, 3]));
+        assert_ne!(TemplateId::new(&[1, 2, 3]), TemplateId::new(&[1, 2, 4]));
+    }
+
+    #[test]
+    fn template_id_is_shown_as_hex() {
+        let id = TemplateId([0xab; 32]);
+        assert_eq!(id.to_string(), "ab".repeat(32));
+    }
 }
@@ -0,0 +1,119 @@
+use std::io::ErrorKind;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+
+use async_std::fs::{create_dir_all, read, rename, OpenOptions};
+use async_std::io::WriteExt;
+use futures::future::BoxFuture;
+use rand::random;
+
+use crate::fingerprint_driver::DriverError;
+
+/// The seed that older versions of rust-fp always used. Templates enrolled with it can only be used with this seed.
+/// It is "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa" so that it can easily be typed manually too.
+/// I'm pretty sure the tradition of using this seed was made by WeirdTreeThing.
+pub const LEGACY_SEED: [u8; 32] = [b'a'; 32];
+
+/// Gives drivers the secret that sensors use to encrypt templates.
+/// Templates can only be decrypted by a sensor with the same seed.
+pub trait KeyProvider: Sync + Send {
+    fn get_seed(&self) -> BoxFuture<'_, Result<[u8; 32], DriverError>>;
+    /// Replaces the seed with a new random seed, so that templates enrolled with the old seed can't be used anymore.
+    /// Sensors only use the new seed after they reboot.
+    fn replace_seed(&self) -> BoxFuture<'_, Result<(), DriverError>>;
+}
+
+/// Keeps the seed in a file that only root can read. A random seed is generated if the file doesn't exist.
+pub struct FileKeyProvider {
+    pub path: PathBuf,
+    /// Use [`LEGACY_SEED`] instead of generating a random seed if the file doesn't exist,
+    /// so that templates enrolled with it keep working until they are enrolled again.
+    /// It is never saved, so a random seed is generated once no templates need it anymore.
+    pub legacy: bool,
+}
+
+impl KeyProvider for FileKeyProvider {
+    fn get_seed(&self) -> BoxFuture<'_, Result<[u8; 32], DriverError>> {
+        Box::pin(async {
+            match read(&self.path).await {
+                Ok(seed) => seed.try_into().map_err(|seed: Vec<u8>| {
+                    DriverError::Io(format!(
+                        "Seed file {:?} has {} bytes instead of 32",
+                        self.path,
+                        seed.len()
+                    ))
+                }),
+                Err(e) if e.kind() == ErrorKind::NotFound && self.legacy => Ok(LEGACY_SEED),
+                Err(e) if e.kind() == ErrorKind::NotFound => {
+                    let seed: [u8; 32] = random();
+                    if let Some(dir) = self.path.parent() {
+                        create_dir_all(dir).await?;
+                    }
+                    let mut file = OpenOptions::new()
+                        .write(true)
+                        .create_new(true)
+                        .mode(0o600)
+                        .open(&self.path)
+                        .await?;
+                    file.write_all(&seed).await?;
+                    file.sync_all().await?;
+                    Ok(seed)
+                }
+                Err(e) => Err(e.into()),
+            }
+        })
+    }
+
+    fn replace_seed(&self) -> BoxFuture<'_, Result<(), DriverError>> {
+        Box::pin(async {
+            let seed: [u8; 32] = random();
+            if let Some(dir) = self.path.parent() {
+                create_dir_all(dir).await?;
+            }
+            // The old seed is only replaced once the new seed is fully written
+            let mut temp_path = self.path.clone().into_os_string();
+            temp_path.push(".tmp");
+            let mut file = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .mode(0o600)
+                .open(&temp_path)
+                .await?;
+            file.write_all(&seed).await?;
+            file.sync_all().await?;
+            rename(&temp_path, &self.path).await?;
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::remove_file;
+
+    use async_std::task::block_on;
+
+    use super::*;
+
+    #[test]
+    fn replace_seed() {
+        let path = std::env::temp_dir().join(format!("rust-fp-test-{}-seed", std::process::id()));
+        let _ = remove_file(&path);
+        let key_provider = FileKeyProvider {
+            path: path.clone(),
+            legacy: true,
+        };
+        block_on(async {
+            assert_eq!(key_provider.get_seed().await.unwrap(), LEGACY_SEED);
+            // The legacy seed is never saved
+            assert!(!path.exists());
+            key_provider.replace_seed().await.unwrap();
+            let seed = key_provider.get_seed().await.unwrap();
+            assert_ne!(seed, LEGACY_SEED);
+            // The new seed is kept
+            assert_eq!(key_provider.get_seed().await.unwrap(), seed);
+        });
+        remove_file(&path).unwrap();
+    }
+}
@@ -2,6 +2,7 @@
 
 pub mod drivers;
 pub mod fingerprint_driver;
+pub mod key_provider;
 
 // use futures::future::join_all;
 // use std::collections::HashMap;